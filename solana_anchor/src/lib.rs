@@ -1,14 +1,26 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Mint, Transfer};
+use anchor_spl::token::{self, Token, TokenAccount, Mint, Transfer, MintTo, Burn};
 use anchor_lang::solana_program::system_instruction::{transfer};
-use anchor_lang::solana_program::program::{invoke_signed};
+use anchor_lang::solana_program::program::{invoke, invoke_signed};
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program_option::COption;
 use anchor_lang::solana_program::Pubkey::Pubkey;
 use metaplex_token_metadata::{
     state::{
+        Key,
+        Metadata,
+        EDITION,
+        MAX_NAME_LENGTH,
         MAX_SYMBOL_LENGTH,
+        PREFIX,
     }
 };
 
+pub mod merkle;
+pub mod rewards;
+
+use rewards::{CollectionSnapshot, EmissionSnapshot, PoolSnapshot, StakeSnapshot};
+
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
 #[program]
@@ -20,6 +32,7 @@ pub mod solana_anchor {
         _bump : u8,
         _start_time : i64,
         _period : i64,
+        _end_time : i64,
         ) -> ProgramResult {
 
         msg!("Initialize");
@@ -27,6 +40,12 @@ pub mod solana_anchor {
         if _start_time <= 0 {
             return Err(PoolError::InvalidTime.into());
         }
+        if _end_time != 0 && _end_time <= _start_time {
+            return Err(PoolError::InvalidTime.into());
+        }
+        if _period <= 0 {
+            return Err(PoolError::InvalidPeriod.into());
+        }
 
         let pool = &mut ctx.accounts.pool;
 
@@ -34,9 +53,23 @@ pub mod solana_anchor {
         pool.rand = *ctx.accounts.rand.key;
         pool.reward_mint = ctx.accounts.reward_mint.key();
         pool.start_time = _start_time;
+        pool.end_time = _end_time;
         pool.period = _period;
-        pool.tvl = 0;
+        pool.stats = StakeStats::default();
         pool.bump = _bump;
+        pool.mode = PoolMode::Fixed;
+        pool.emission_rate = 0;
+        pool.reward_per_weight = 0;
+        pool.total_weight = 0;
+        pool.last_update_time = _start_time;
+        pool.schedule = EmissionSchedule::Flat;
+        pool.vesting_cliff = 0;
+        pool.vesting_duration = 0;
+        pool.total_owed = 0;
+        pool.early_unlock_enabled = false;
+        pool.unbonding_period = 0;
+        pool.max_per_wallet = 0;
+        pool.status = PoolStatus::Active;
 
         Ok(())
     }
@@ -45,7 +78,7 @@ pub mod solana_anchor {
         ctx : Context<UpdatePool>,
         _start_time : i64,
         _period : i64,
-        _tvl : u8
+        _end_time : i64
         ) -> ProgramResult {
 
         msg!("Update");
@@ -55,12 +88,63 @@ pub mod solana_anchor {
         if _start_time == 0 {
             return Err(PoolError::InvalidTime.into());
         }
+        if _end_time != 0 && _end_time <= _start_time {
+            return Err(PoolError::InvalidTime.into());
+        }
+        if _period <= 0 {
+            return Err(PoolError::InvalidPeriod.into());
+        }
+
+        // Emissions so far were priced with the old window and period.
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.update_emission(clock.unix_timestamp)?;
 
         pool.owner = *ctx.accounts.new_owner.key;
         pool.reward_mint = ctx.accounts.reward_mint.key();
         pool.start_time = _start_time;
+        pool.end_time = _end_time;
         pool.period = _period;
-        pool.tvl = _tvl;
+
+        Ok(())
+    }
+
+    pub fn set_emission(
+        ctx : Context<UpdatePoolConfig>,
+        _mode : PoolMode,
+        _emission_rate : u64
+        ) -> ProgramResult {
+
+        msg!("Set emission");
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+
+        if _mode != pool.mode && pool.stats.staked_count != 0 {
+            msg!("Pool mode can only change while nothing is staked");
+            return Err(PoolError::InvalidPoolMode.into());
+        }
+
+        pool.update_emission(clock.unix_timestamp)?;
+        pool.mode = _mode;
+        pool.emission_rate = _emission_rate;
+
+        Ok(())
+    }
+
+    pub fn set_schedule(
+        ctx : Context<UpdatePoolConfig>,
+        _schedule : EmissionSchedule
+        ) -> ProgramResult {
+
+        msg!("Set schedule");
+
+        _schedule.validate()?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+
+        pool.update_emission(clock.unix_timestamp)?;
+        pool.schedule = _schedule;
 
         Ok(())
     }
@@ -69,25 +153,32 @@ pub mod solana_anchor {
         ctx : Context<InitCollection>,
         _bump : u8,
         _reward_normal : u64,
-        _reward_special_one : u64,
-        _reward_special_two : u64,
-        _reward_special_three : u64,
-        _ultras : Vec<Pubkey>
+        _lock_options : Vec<LockOption>,
+        _rarity_root : [u8; 32],
+        _policy : CollectionPolicy
     ) -> ProgramResult {
 
         msg!("Init Collection");
 
+        Collection::validate_lock_options(&_lock_options)?;
+
         let collection_data = &mut ctx.accounts.collection_data;
         
         collection_data.owner = *ctx.accounts.owner.key;
         collection_data.bump = _bump;
         collection_data.pool = ctx.accounts.pool.key();
         collection_data.reward_normal = _reward_normal;
-        collection_data.reward_locked_one = _reward_special_one;
-        collection_data.reward_locked_two = _reward_special_two;
-        collection_data.reward_locked_three = _reward_special_three;
+        collection_data.lock_options = _lock_options;
         collection_data.creator = *ctx.accounts.creator.key;
-        collection_data.ultras = _ultras;
+        collection_data.rarity_root = _rarity_root;
+        collection_data.start_time = 0;
+        collection_data.end_time = 0;
+        collection_data.rate_history = Vec::new();
+        collection_data.policy = _policy;
+        collection_data.stats = StakeStats::default();
+        collection_data.lock_stats = Vec::new();
+        collection_data.max_staked = 0;
+        collection_data.eligibility = Eligibility::legacy();
 
         Ok(())
     }
@@ -95,21 +186,81 @@ pub mod solana_anchor {
     pub fn update_collection(
         ctx : Context<UpdateCollection>,
         _reward_normal : u64,
-        _reward_special_one : u64,
-        _reward_special_two : u64,
-        _reward_special_three : u64,
-        _ultras : Vec<Pubkey>
+        _lock_options : Vec<LockOption>,
+        _rarity_root : [u8; 32]
     ) -> ProgramResult {
 
         msg!("Init Collection");
+
+        Collection::validate_lock_options(&_lock_options)?;
         
         let collection_data = &mut ctx.accounts.collection_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
 
+        collection_data.checkpoint_rates(clock.unix_timestamp)?;
         collection_data.reward_normal = _reward_normal;
-        collection_data.reward_locked_one = _reward_special_one;
-        collection_data.reward_locked_two = _reward_special_two;
-        collection_data.reward_locked_three = _reward_special_three;
-        collection_data.ultras = _ultras;
+        collection_data.lock_options = _lock_options;
+        collection_data.rarity_root = _rarity_root;
+
+        Ok(())
+    }
+
+    pub fn set_collection_policy(
+        ctx : Context<UpdateCollection>,
+        _policy : CollectionPolicy
+    ) -> ProgramResult {
+
+        msg!("Set collection policy");
+
+        let collection_data = &mut ctx.accounts.collection_data;
+
+        collection_data.policy = _policy;
+
+        Ok(())
+    }
+
+    pub fn set_collection_limit(
+        ctx : Context<UpdateCollection>,
+        _max_staked : u64
+    ) -> ProgramResult {
+
+        msg!("Set collection limit");
+
+        ctx.accounts.collection_data.max_staked = _max_staked;
+
+        Ok(())
+    }
+
+    pub fn set_collection_eligibility(
+        ctx : Context<UpdateCollection>,
+        _eligibility : Eligibility
+    ) -> ProgramResult {
+
+        msg!("Set collection eligibility");
+
+        _eligibility.validate()?;
+
+        ctx.accounts.collection_data.eligibility = _eligibility;
+
+        Ok(())
+    }
+
+    pub fn set_collection_window(
+        ctx : Context<UpdateCollection>,
+        _start_time : i64,
+        _end_time : i64
+    ) -> ProgramResult {
+
+        msg!("Set collection window");
+
+        if _start_time < 0 || (_end_time != 0 && _end_time <= _start_time) {
+            return Err(PoolError::InvalidTime.into());
+        }
+
+        let collection_data = &mut ctx.accounts.collection_data;
+
+        collection_data.start_time = _start_time;
+        collection_data.end_time = _end_time;
 
         Ok(())
     }
@@ -120,57 +271,86 @@ pub mod solana_anchor {
     ) -> ProgramResult {
         msg!("InitNft");
 
-        let stake_data = &mut ctx.accounts.stake_data;
-        stake_data.bump = _bump;
-        stake_data.locked = false;
-        stake_data.lock_period = 0;
-        stake_data.owner = *ctx.accounts.owner.key;
-        stake_data.mint = ctx.accounts.nft_mint.key();
-        stake_data.pool = ctx.accounts.pool.key();
-        stake_data.unstaked = true;
-        stake_data.last_claim_time = 0;
-        stake_data.stake_time = 0;
+        init_stake_account(
+            &mut ctx.accounts.stake_data,
+            _bump,
+            ctx.accounts.owner.key(),
+            ctx.accounts.nft_mint.key(),
+            ctx.accounts.pool.key(),
+        );
         
         Ok(())
     }
 
-    pub fn stake(
-        ctx : Context<Stake>,
+    pub fn init_wallet(
+        ctx : Context<InitWallet>,
+        _bump : u8,
+    ) -> ProgramResult {
+        msg!("+ init wallet");
+
+        let wallet = &mut ctx.accounts.wallet;
+        wallet.owner = *ctx.accounts.owner.key;
+        wallet.pool = ctx.accounts.pool.key();
+        wallet.staked_count = 0;
+        wallet.bump = _bump;
+
+        Ok(())
+    }
+
+    /// Closes an unstaked `StakeData` that is owed nothing and returns its
+    /// rent to the owner. Stream rewards the stake has not claimed yet are
+    /// given up with it.
+    pub fn close_stake_data(
+        ctx : Context<CloseStakeData>,
+    ) -> ProgramResult {
+        msg!("+close stake data");
+
+        let stake_data = &ctx.accounts.stake_data;
+        if !stake_data.unstaked || stake_data.owed > 0 {
+            msg!("Stake is still active or owed rewards");
+            return Err(PoolError::StakeDataInUse.into());
+        }
+
+        Ok(())
+    }
+
+    /// `init_stake_data` and `stake` in one instruction, for a mint the owner
+    /// has no `StakeData` for yet.
+    pub fn init_and_stake(
+        ctx : Context<InitAndStake>,
+        _bump : u8,
         locked : bool,
-        lock_period : u64
+        lock_period : u64,
+        rarity_multiplier_bps : u32,
+        rarity_proof : Vec<[u8; 32]>
         ) -> ProgramResult {
-        msg!("+Stake");
+        msg!("+init and stake");
 
         let pool = &mut ctx.accounts.pool;
-        let collection_data = &ctx.accounts.collection_data;
+        let collection_data = &mut ctx.accounts.collection_data;
         let clock = Clock::from_account_info(&ctx.accounts.clock)?;
-        let metadata : metaplex_token_metadata::state::Metadata =  metaplex_token_metadata::state::Metadata::from_account_info(&ctx.accounts.metadata)?;
         let nft_mint = &ctx.accounts.nft_mint;
 
-        if nft_mint.decimals != 0 && nft_mint.supply != 1 {
-            msg!("This mint is not proper nft");
-            return Err(PoolError::InvalidTokenMint.into());
-        }
-        if metadata.mint != ctx.accounts.nft_mint.key() {
-            msg!("Not match mint address");
-            return Err(PoolError::InvalidMetadata.into());
-        }
-        if (&metadata.data.symbol).eq("IV") {
-            msg!("Not match collection");
-            return Err(PoolError::InvalidMetadata.into());
-        }
-        if metadata.data.creators.is_some(){
-            if let Some(creators) = &metadata.data.creators{
-                if creators[0].address != collection_data.creator {
-                    msg!("Not match collection");
-                    return Err(PoolError::InvalidMetadata.into());
-                }
-            }
-        }
-        if !metadata.primary_sale_happened {
-            msg!("Not match collection");
-            return Err(PoolError::InvalidMetadata.into());
-        }
+        init_stake_account(
+            &mut ctx.accounts.stake_data,
+            _bump,
+            ctx.accounts.owner.key(),
+            nft_mint.key(),
+            pool.key(),
+        );
+
+        let multiplier_bps = check_stake(
+            pool,
+            collection_data,
+            &ctx.accounts.metadata,
+            &ctx.accounts.edition,
+            nft_mint,
+            locked,
+            lock_period,
+            rarity_multiplier_bps,
+            &rarity_proof,
+            clock.unix_timestamp,
+        )?;
 
         let cpi_accounts = Transfer {
             from: ctx.accounts.user_nft_account.to_account_info().clone(),
@@ -184,790 +364,3545 @@ pub mod solana_anchor {
 
         token::transfer(token_cpi_ctx, 1)?;
 
-        let stake_data = &mut ctx.accounts.stake_data;
-        stake_data.unstaked = false;
-        stake_data.stake_time = clock.unix_timestamp;
-        stake_data.last_claim_time = clock.unix_timestamp;
-        stake_data.locked = locked;
-        stake_data.lock_period = lock_period;
-
-        pool.tvl += 1;
-
-        Ok(())
+        open_stake(
+            pool,
+            collection_data,
+            &mut ctx.accounts.wallet,
+            &mut ctx.accounts.stake_data,
+            locked,
+            lock_period,
+            multiplier_bps,
+            false,
+            clock.unix_timestamp,
+        )
     }
 
-    pub fn unstake(
-        ctx : Context<Unstake>,
+    /// Stakes an NFT into the pool's custody. With `with_receipt` the position
+    /// is issued a receipt on the spot, as `issue_receipt` would: the first two
+    /// `remaining_accounts` are then a fresh receipt mint, with the pool as its
+    /// mint authority and no supply, and the owner's token account for it.
+    pub fn stake<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, Stake<'info>>,
+        locked : bool,
+        lock_period : u64,
+        rarity_multiplier_bps : u32,
+        rarity_proof : Vec<[u8; 32]>,
+        with_receipt : bool
         ) -> ProgramResult {
-        msg!("+unstake");
+        msg!("+Stake");
 
         let pool = &mut ctx.accounts.pool;
-        let collection_data = &ctx.accounts.collection_data;
-        let stake_data = &mut ctx.accounts.stake_data;
-
+        let collection_data = &mut ctx.accounts.collection_data;
         let clock = Clock::from_account_info(&ctx.accounts.clock)?;
-        let metadata : metaplex_token_metadata::state::Metadata =  metaplex_token_metadata::state::Metadata::from_account_info(&ctx.accounts.metadata)?;
         let nft_mint = &ctx.accounts.nft_mint;
 
-        if stake_data.unstaked {
-            return Err(PoolError::AlreadyUnstaked.into());
-        }
-        if stake_data.owner != *ctx.accounts.owner.key {
-            return Err(PoolError::InvalidOwner.into());
-        }
-        if stake_data.locked && (clock.unix_timestamp - stake_data.stake_time) <= pool.period * stake_data.lock_period as i64 && *ctx.accounts.owner.key != pool.owner {
-            return Err(PoolError::InvalidTime.into());
-        }
-        if nft_mint.decimals != 0 && nft_mint.supply != 1 {
-            msg!("This mint is not proper nft");
-            return Err(PoolError::InvalidTokenMint.into());
-        }
-        if metadata.mint != ctx.accounts.nft_mint.key() {
-            msg!("Not match mint address");
-            return Err(PoolError::InvalidMetadata.into());
-        }
-        if (&metadata.data.symbol).eq("IV") {
-            msg!("Not match collection");
-            return Err(PoolError::InvalidMetadata.into());
-        }
-        if metadata.data.creators.is_some(){
-            if let Some(creators) = &metadata.data.creators{
-                if creators[0].address != collection_data.creator {
-                    msg!("Not match collection");
-                    return Err(PoolError::InvalidMetadata.into());
-                }
-            }
-        }
-        if !metadata.primary_sale_happened {
-            msg!("Not match collection");
-            return Err(PoolError::InvalidMetadata.into());
-        }
-
-        let mut total_reward = 0;
-        if stake_data.locked {
-            if (clock.unix_timestamp - stake_data.stake_time) > (stake_data.lock_period as i64) * pool.period {
-                if (stake_data.last_claim_time - stake_data.stake_time) > (stake_data.lock_period as i64) * pool.period {
-                    let mut reward = collection_data.reward_normal;
-                    
-                    for ultra in &collection_data.ultras {
-                        if stake_data.mint == *ultra {
-                            reward = reward * 2;
-                        }
-                    }
-                    total_reward = reward * (clock.unix_timestamp - stake_data.last_claim_time) as u64 / pool.period as u64;
-                } else {
-                    let mut lock_reward = collection_data.reward_locked_one;
-                    if stake_data.lock_period == 30 {
-                        lock_reward = collection_data.reward_locked_two;
-                    }
-                    if stake_data.lock_period == 60 {
-                        lock_reward = collection_data.reward_locked_three;
-                    }
-                    let mut reward = collection_data.reward_normal;
-                    for ultra in &collection_data.ultras {
-                        if stake_data.mint == *ultra {
-                            lock_reward = lock_reward * 2;
-                            reward = reward * 2;
-                        }
-                    }
-                    total_reward = (lock_reward * ((stake_data.stake_time + stake_data.lock_period as i64 * pool.period) - stake_data.last_claim_time) as u64 + reward * (clock.unix_timestamp as u64 - (stake_data.stake_time + stake_data.lock_period as i64 * pool.period) as u64)) / pool.period as u64;
-                }
-            } else {
-                let mut lock_reward = collection_data.reward_locked_one;
-                if stake_data.lock_period == 30 {
-                    lock_reward = collection_data.reward_locked_two;
-                }
-                if stake_data.lock_period == 60 {
-                    lock_reward = collection_data.reward_locked_three;
-                }
-                for ultra in &collection_data.ultras {
-                    if stake_data.mint == *ultra {
-                        lock_reward = lock_reward * 2;
-                    }
-                }
-                total_reward = lock_reward * (clock.unix_timestamp - stake_data.last_claim_time) as u64 / pool.period as u64;
-            }
-        } else {
-            let mut reward = collection_data.reward_normal;
-        
-            for ultra in &collection_data.ultras {
-                if stake_data.mint == *ultra {
-                    reward = reward * 2;
-                }
-            }
-            total_reward = reward * (clock.unix_timestamp - stake_data.last_claim_time) as u64 / pool.period as u64 ;
-        }
+        let multiplier_bps = check_stake(
+            pool,
+            collection_data,
+            &ctx.accounts.metadata,
+            &ctx.accounts.edition,
+            nft_mint,
+            locked,
+            lock_period,
+            rarity_multiplier_bps,
+            &rarity_proof,
+            clock.unix_timestamp,
+        )?;
 
         let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_reward_account.to_account_info().clone(),
-            to: ctx.accounts.user_reward_account.to_account_info().clone(),
-            authority: pool.to_account_info().clone(),
+            from: ctx.accounts.user_nft_account.to_account_info().clone(),
+            to: ctx.accounts.pool_nft_account.to_account_info().clone(),
+            authority: ctx.accounts.owner.to_account_info().clone(),
         };
 
-        let signer_seeds = &[
-            pool.rand.as_ref(),
-            &[pool.bump],
-        ];
-
-        let signer = &[&signer_seeds[..]];
-
         let cpi_program = ctx.accounts.token_program.to_account_info().clone();
 
-        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
-
-        if *ctx.accounts.owner.key == pool.owner {
-            total_reward = total_reward * 20;
-        }
-
-        token::transfer(token_cpi_ctx, total_reward)?;
-
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_nft_account.to_account_info().clone(),
-            to: ctx.accounts.user_nft_account.to_account_info().clone(),
-            authority: pool.to_account_info().clone(),
-        };
+        let token_cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
 
-        let signer_seeds = &[
-            pool.rand.as_ref(),
-            &[pool.bump],
-        ];
+        token::transfer(token_cpi_ctx, 1)?;
 
-        let signer = &[&signer_seeds[..]];
+        open_stake(
+            pool,
+            collection_data,
+            &mut ctx.accounts.wallet,
+            &mut ctx.accounts.stake_data,
+            locked,
+            lock_period,
+            multiplier_bps,
+            false,
+            clock.unix_timestamp,
+        )?;
+
+        if with_receipt {
+            if ctx.remaining_accounts.len() < 2 {
+                msg!("Receipt mint and account are required");
+                return Err(PoolError::ReceiptRequired.into());
+            }
+            let receipt_mint : Account<Mint> = Account::try_from(&ctx.remaining_accounts[0])?;
+            let receipt_account : Account<TokenAccount> = Account::try_from(&ctx.remaining_accounts[1])?;
+            if receipt_mint.mint_authority != COption::Some(pool.key())
+                || receipt_mint.supply != 0
+                || receipt_mint.freeze_authority.is_some()
+                || receipt_mint.decimals != 0
+                || receipt_account.mint != receipt_mint.key()
+                || receipt_account.owner != ctx.accounts.owner.key() {
+                msg!("Receipt mint must be new and minted by the pool");
+                return Err(PoolError::InvalidTokenMint.into());
+            }
+            mint_receipt(
+                pool,
+                &mut ctx.accounts.stake_data,
+                receipt_mint.to_account_info(),
+                receipt_account.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+            )?;
+        }
 
-        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+        Ok(())
+    }
 
-        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+    /// Stakes an NFT without moving it: the pool is approved as delegate and
+    /// freezes the holder's token account through the edition's freeze
+    /// authority, so the NFT stays in the holder's wallet until
+    /// `soft_unstake`.
+    pub fn soft_stake(
+        ctx : Context<SoftStake>,
+        locked : bool,
+        lock_period : u64,
+        rarity_multiplier_bps : u32,
+        rarity_proof : Vec<[u8; 32]>
+        ) -> ProgramResult {
+        msg!("+soft stake");
 
-        token::transfer(token_cpi_ctx, 1)?;
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        let nft_mint = &ctx.accounts.nft_mint;
 
-        stake_data.unstaked = true;
-        stake_data.locked = false;
-        stake_data.lock_period = 0;
-        pool.tvl -= 1;
-        
-        Ok(())
+        let multiplier_bps = check_stake(
+            pool,
+            collection_data,
+            &ctx.accounts.metadata,
+            &ctx.accounts.edition,
+            nft_mint,
+            locked,
+            lock_period,
+            rarity_multiplier_bps,
+            &rarity_proof,
+            clock.unix_timestamp,
+        )?;
+
+        invoke(
+            &spl_token::instruction::approve(
+                &spl_token::id(),
+                &ctx.accounts.user_nft_account.key(),
+                &pool.key(),
+                ctx.accounts.owner.key,
+                &[],
+                1,
+            )?,
+            &[
+                ctx.accounts.user_nft_account.to_account_info(),
+                pool.to_account_info(),
+                ctx.accounts.owner.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+            ],
+        )?;
+
+        delegated_freeze(
+            pool,
+            FREEZE_DELEGATED_ACCOUNT,
+            SoftStakeAccounts {
+                user_nft_account : ctx.accounts.user_nft_account.to_account_info(),
+                edition : ctx.accounts.edition.clone(),
+                nft_mint : nft_mint.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+                token_metadata_program : ctx.accounts.token_metadata_program.clone(),
+            },
+        )?;
+
+        open_stake(
+            pool,
+            collection_data,
+            &mut ctx.accounts.wallet,
+            &mut ctx.accounts.stake_data,
+            locked,
+            lock_period,
+            multiplier_bps,
+            true,
+            clock.unix_timestamp,
+        )
     }
 
-    pub fn withdraw(
-        ctx : Context<Withdraw>
+    /// Ends a soft stake: pays out its rewards like `unstake`, then thaws the
+    /// holder's token account and revokes the pool's delegation.
+    pub fn soft_unstake<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, SoftUnstake<'info>>,
         ) -> ProgramResult {
-        msg!("+unstake");
-
-        let pool = &ctx.accounts.pool;
+        msg!("+soft unstake");
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_nft_account.to_account_info().clone(),
-            to: ctx.accounts.user_nft_account.to_account_info().clone(),
-            authority: pool.to_account_info().clone()
-        };
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
 
-        let signer_seeds = &[
-            pool.rand.as_ref(),
-            &[pool.bump]
-        ];
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+        let nft_mint = &ctx.accounts.nft_mint;
 
-        let signer = &[&signer_seeds[..]];
+        if stake_data.unstaked {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        if !stake_data.soft {
+            msg!("Stake is held by the pool, use unstake");
+            return Err(PoolError::InvalidStakeKind.into());
+        }
+        verify_released(pool, stake_data, clock.unix_timestamp)?;
+        if lock_active(pool, stake_data, clock.unix_timestamp)? && *ctx.accounts.owner.key != pool.owner {
+            return Err(PoolError::InvalidTime.into());
+        }
+        verify_nft_mint(nft_mint)?;
+        verify_metadata_account(&ctx.accounts.metadata, &nft_mint.key())?;
 
-        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+        let mut total_reward = pool.settle_accrued(collection_data, stake_data, clock.unix_timestamp)?;
+        pool.set_stake_weight(stake_data, 0)?;
 
-        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        if pool.mode == PoolMode::Fixed && *ctx.accounts.owner.key == pool.owner {
+            total_reward = total_reward.checked_mul(20).ok_or(PoolError::MathOverflow)?;
+        }
 
-        token::transfer(token_cpi_ctx, 1);
+        let paid = pool.record_payout(collection_data, stake_data, total_reward, ctx.accounts.pool_reward_account.amount)?;
+
+        pay_reward(
+            pool,
+            ctx.accounts.owner.key,
+            RewardAccounts {
+                pool_reward_account : ctx.accounts.pool_reward_account.to_account_info(),
+                user_reward_account : ctx.accounts.user_reward_account.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+            },
+            ctx.remaining_accounts,
+            paid,
+            clock.unix_timestamp,
+            ctx.program_id,
+        )?;
+
+        release_soft_stake(
+            pool,
+            SoftStakeAccounts {
+                user_nft_account : ctx.accounts.user_nft_account.to_account_info(),
+                edition : ctx.accounts.edition.clone(),
+                nft_mint : nft_mint.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+                token_metadata_program : ctx.accounts.token_metadata_program.clone(),
+            },
+            ctx.accounts.owner.to_account_info(),
+        )?;
+
+        pool.close_stake(collection_data, &mut ctx.accounts.wallet, stake_data, clock.unix_timestamp)?;
 
         Ok(())
     }
 
-    pub fn withdraw_request(
-        ctx : Context<Withdraw>,
-        _request_amount : u64
-    ) -> ProgramResult {
-
-        let pool = &ctx.accounts.pool;
+    /// Returns a custodial NFT without paying or computing rewards; only
+    /// available while the pool is in `PoolStatus::Emergency`. Rewards still
+    /// owed stay on the books. A position with a receipt expects the holder's
+    /// receipt token account as the first remaining account.
+    pub fn emergency_unstake<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, EmergencyUnstake<'info>>,
+        ) -> ProgramResult {
+        msg!("+emergency unstake");
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_nft_account.to_account_info().clone(),
-            to: ctx.accounts.user_nft_account.to_account_info().clone(),
-            authority: pool.to_account_info().clone()
-        };
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
 
-        let signer_seeds = &[pool.rand.as_ref(), &[pool.bump]];
-        let signer = &[&signer_seeds[..]];
+        pool.status.verify_emergency()?;
+        verify_position_authority(stake_data, ctx.accounts.authority.key, ctx.remaining_accounts)?;
+        if stake_data.unstaked {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        if stake_data.soft {
+            msg!("Soft stakes are released with emergency_soft_unstake");
+            return Err(PoolError::InvalidStakeKind.into());
+        }
 
-        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+        pool.update_emission(clock.unix_timestamp)?;
+        pool.set_stake_weight(stake_data, 0)?;
 
-        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        transfer_from_pool(
+            pool,
+            ctx.accounts.pool_nft_account.to_account_info(),
+            ctx.accounts.user_nft_account.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            1,
+        )?;
 
-        token::transfer(token_cpi_context, _request_amount);
+        pool.close_stake(collection_data, &mut ctx.accounts.wallet, stake_data, clock.unix_timestamp)?;
+        if stake_data.owed == 0 {
+            stake_data.receipt_mint = Pubkey::default();
+        }
 
         Ok(())
     }
 
-    pub fn claim(
-        ctx : Context<Claim>
+    /// `emergency_unstake` for soft stakes: thaws the NFT and revokes the
+    /// pool's delegation without touching rewards. The reward accounts of
+    /// `SoftUnstake` are not used.
+    pub fn emergency_soft_unstake(
+        ctx : Context<SoftUnstake>,
         ) -> ProgramResult {
-        let pool = &ctx.accounts.pool;
-        let collection_data = &ctx.accounts.collection_data;
+        msg!("+emergency soft unstake");
+
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
         let stake_data = &mut ctx.accounts.stake_data;
         let clock = Clock::from_account_info(&ctx.accounts.clock)?;
-        let metadata : metaplex_token_metadata::state::Metadata =  metaplex_token_metadata::state::Metadata::from_account_info(&ctx.accounts.metadata)?;
 
+        pool.status.verify_emergency()?;
         if stake_data.unstaked {
             return Err(PoolError::AlreadyUnstaked.into());
         }
-        if (&metadata.data.symbol).eq("IV") {
-            msg!("Not match collection");
-            return Err(PoolError::InvalidMetadata.into());
-        }
-        if metadata.data.creators.is_some(){
-            if let Some(creators) = &metadata.data.creators{
-                if creators[0].address != collection_data.creator {
-                    msg!("Not match collection");
-                    return Err(PoolError::InvalidMetadata.into());
-                }
-            }
-        }
-        if !metadata.primary_sale_happened {
-            msg!("Not match collection");
-            return Err(PoolError::InvalidMetadata.into());
+        if !stake_data.soft {
+            msg!("Stake is held by the pool, use emergency_unstake");
+            return Err(PoolError::InvalidStakeKind.into());
         }
-        
-        let mut total_reward = 0;
-        if stake_data.locked {
-            if (clock.unix_timestamp - stake_data.stake_time) > (stake_data.lock_period as i64) * pool.period {
-                if (stake_data.last_claim_time - stake_data.stake_time) > (stake_data.lock_period as i64) * pool.period {
-                    let mut reward = collection_data.reward_normal;
-                    
-                    for ultra in &collection_data.ultras {
-                        if stake_data.mint == *ultra {
-                            reward = reward * 2;
-                        }
-                    }
-                    total_reward = reward * (clock.unix_timestamp - stake_data.last_claim_time) as u64 / pool.period as u64;
-                } else {
-                    let mut lock_reward = collection_data.reward_locked_one;
-                    if stake_data.lock_period == 30 {
-                        lock_reward = collection_data.reward_locked_two;
-                    }
-                    if stake_data.lock_period == 60 {
-                        lock_reward = collection_data.reward_locked_three;
-                    }
-                    let mut reward = collection_data.reward_normal; 
-                    for ultra in &collection_data.ultras {
-                        if stake_data.mint == *ultra {
-                            lock_reward = lock_reward * 2;
-                            reward = reward * 2;
-                        }
-                    }
-                    total_reward = (lock_reward * ((stake_data.stake_time + stake_data.lock_period as i64 * pool.period) - stake_data.last_claim_time) as u64 + reward * (clock.unix_timestamp as u64 - (stake_data.stake_time + stake_data.lock_period as i64 * pool.period) as u64)) / pool.period as u64;
-                }
-            } else {
-                let mut lock_reward = collection_data.reward_locked_one;
-                if stake_data.lock_period == 30 {
-                    lock_reward = collection_data.reward_locked_two;
-                }
-                if stake_data.lock_period == 60 {
-                    lock_reward = collection_data.reward_locked_three;
-                }
-                for ultra in &collection_data.ultras {
-                    if stake_data.mint == *ultra {
-                        lock_reward = lock_reward * 2;
-                    }
-                }
-                total_reward = lock_reward * (clock.unix_timestamp - stake_data.last_claim_time) as u64 / pool.period as u64;
-            }
-        } else {
-            let mut reward = collection_data.reward_normal;
-        
-            for ultra in &collection_data.ultras {
-                if stake_data.mint == *ultra {
-                    reward = reward * 2;
-                }
-            }
-            total_reward = reward * (clock.unix_timestamp - stake_data.last_claim_time) as u64 / pool.period as u64 ;
-        }
-
-        if *ctx.accounts.owner.key == pool.owner {
-            total_reward = total_reward * 50;
-        }
-
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_reward_account.to_account_info().clone(),
-            to: ctx.accounts.user_reward_account.to_account_info().clone(),
-            authority: pool.to_account_info().clone(),
-        };
-
-        let signer_seeds = &[
-            pool.rand.as_ref(),
-            &[pool.bump],
-        ]
-
-        let signer = &[&signer_seeds[..]];
-
-        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
 
-        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        pool.update_emission(clock.unix_timestamp)?;
+        pool.set_stake_weight(stake_data, 0)?;
 
-        token::transfer(token_cpi_ctx, total_reward)?;
+        release_soft_stake(
+            pool,
+            SoftStakeAccounts {
+                user_nft_account : ctx.accounts.user_nft_account.to_account_info(),
+                edition : ctx.accounts.edition.clone(),
+                nft_mint : ctx.accounts.nft_mint.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+                token_metadata_program : ctx.accounts.token_metadata_program.clone(),
+            },
+            ctx.accounts.owner.to_account_info(),
+        )?;
 
-        stake_data.last_claim_time = clock.unix_timestamp;
+        pool.close_stake(collection_data, &mut ctx.accounts.wallet, stake_data, clock.unix_timestamp)?;
 
         Ok(())
     }
 
-    pub fn init_tier(
-        ctx : Context<InitTier>,
-        _bump : u8,
-        _seed :  String,
-        _share : u32,
-        _points : u8,
-        _tokens : u64,
+    pub fn set_status(
+        ctx : Context<UpdatePoolConfig>,
+        _status : PoolStatus
         ) -> ProgramResult {
+        msg!("+ set status");
 
-        msg!("+ Init tier");
-
-        let tier = &mut ctx.accounts.tier;
-
-        tier.owner = *ctx.accounts.owner.key;
-        tier.pool = ctx.accounts.pool.key();
-        tier.share = _share;
-        tier.points = _points;
-        tier.tokens = _tokens;
-        tier.count = 0;
-        tier.royalty = 0;
-        tier.claim_start = 0;
-        tier.seed = _seed;
-        tier.bump = _bump;
+        ctx.accounts.pool.status = _status;
 
         Ok(())
     }
-    
-    pub fn create_clan(
-        ctx : Context<CreateClan>,
-        _bump : u8,
-        _mints : Vec<Pubkey>
-    ) -> ProgramResult {
-        msg!("+ create clan");
+
+    pub fn unstake<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, Unstake<'info>>,
+        ) -> ProgramResult {
+        msg!("+unstake");
+
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
 
         let clock = Clock::from_account_info(&ctx.accounts.clock)?;
-        let clan_data = &mut ctx.accounts.clan_data;
-        let tier = &mut ctx.accounts.tier;
+        pool.status.verify_rewards()?;
+        let nft_mint = &ctx.accounts.nft_mint;
 
-        clan_data.owner = *ctx.accounts.owner.key;
-        clan_data.tier = tier.key();
-        clan_data.rand = *ctx.accounts.rand.key;
-        clan_data.active = true;
-        clan_data.create_time = clock.unix_timestamp;
-        clan_data.last_claim_time = 0;
-        clan_data.nfts = _mints;
-        clan_data.bump = _bump;
+        if stake_data.unstaked {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        verify_owner_held(stake_data)?;
+        verify_released(pool, stake_data, clock.unix_timestamp)?;
+        if stake_data.owner != *ctx.accounts.owner.key {
+            return Err(PoolError::InvalidOwner.into());
+        }
+        if stake_data.soft {
+            msg!("Soft stakes are released with soft_unstake");
+            return Err(PoolError::InvalidStakeKind.into());
+        }
+        if lock_active(pool, stake_data, clock.unix_timestamp)? && *ctx.accounts.owner.key != pool.owner {
+            return Err(PoolError::InvalidTime.into());
+        }
+        verify_nft_mint(nft_mint)?;
+        verify_metadata_account(&ctx.accounts.metadata, &nft_mint.key())?;
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.user_reward_account.to_account_info().clone(),
-            to: ctx.accounts.pool_reward_account.to_account_info().clone(),
-            authority: ctx.accounts.owner.to_account_info().clone(),
-        };
+        let mut total_reward = pool.settle_accrued(collection_data, stake_data, clock.unix_timestamp)?;
+        pool.set_stake_weight(stake_data, 0)?;
 
-        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+        if pool.mode == PoolMode::Fixed && *ctx.accounts.owner.key == pool.owner {
+            total_reward = total_reward.checked_mul(20).ok_or(PoolError::MathOverflow)?;
+        }
 
-        let token_cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        let paid = pool.record_payout(collection_data, stake_data, total_reward, ctx.accounts.pool_reward_account.amount)?;
+
+        pay_reward(
+            pool,
+            ctx.accounts.owner.key,
+            RewardAccounts {
+                pool_reward_account : ctx.accounts.pool_reward_account.to_account_info(),
+                user_reward_account : ctx.accounts.user_reward_account.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+            },
+            ctx.remaining_accounts,
+            paid,
+            clock.unix_timestamp,
+            ctx.program_id,
+        )?;
+
+        transfer_from_pool(
+            pool,
+            ctx.accounts.pool_nft_account.to_account_info(),
+            ctx.accounts.user_nft_account.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            1,
+        )?;
+
+        pool.close_stake(collection_data, &mut ctx.accounts.wallet, stake_data, clock.unix_timestamp)?;
+        
+        Ok(())
+    }
 
-        token::transfer(token_cpi_ctx, tier.tokens)?;
+    pub fn set_wallet_limit(
+        ctx : Context<UpdatePoolConfig>,
+        _max_per_wallet : u64
+        ) -> ProgramResult {
+        msg!("+ set wallet limit");
 
-        tier.count = tier.count + 1;
+        ctx.accounts.pool.max_per_wallet = _max_per_wallet;
 
         Ok(())
     }
 
-    pub fn create_company(
-        ctx : Context<CreateCompany>,
-        _bump : u8,
-        _mints : Vec<Pubkey>
-    ) -> ProgramResult {
-        msg!("+ create company");
+    pub fn set_unbonding(
+        ctx : Context<UpdatePoolConfig>,
+        _unbonding_period : i64
+        ) -> ProgramResult {
+        msg!("+ set unbonding");
 
-        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
-        let company_data = &mut ctx.accounts.company_data;
-        let tier = &mut ctx.accounts.tier;
+        if _unbonding_period < 0 {
+            return Err(PoolError::InvalidTime.into());
+        }
 
-        company_data.owner = *ctx.accounts.owner.key;
-        company_data.tier = tier.key();
-        company_data.rand = *ctx.accounts.rand.key;
-        company_data.active = true;
-        company_data.create_time = clock.unix_timestamp;
-        company_data.last_claim_time = 0;
-        company_data.nfts = _mints;
-        company_data.bump = _bump;
+        ctx.accounts.pool.unbonding_period = _unbonding_period;
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.user_reward_account.to_account_info().clone(),
-            to: ctx.accounts.pool_reward_account.to_account_info().clone(),
-            authority: ctx.accounts.owner.to_account_info().clone(),
-        };
+        Ok(())
+    }
 
-        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+    pub fn set_early_unlock(
+        ctx : Context<SetEarlyUnlock>,
+        _enabled : bool,
+        _penalty_bps : u64,
+        _fee : u64
+        ) -> ProgramResult {
+        msg!("+ set early unlock");
 
-        let token_cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        if _penalty_bps > rewards::BPS {
+            return Err(PoolError::InvalidPenalty.into());
+        }
 
-        token::transfer(token_cpi_ctx, tier.tokens)?;
+        let pool = &mut ctx.accounts.pool;
 
-        tier.count = tier.count + 1;
+        pool.early_unlock_enabled = _enabled;
+        pool.early_unlock_penalty_bps = _penalty_bps;
+        pool.early_unlock_fee = _fee;
+        pool.treasury = ctx.accounts.treasury_account.key();
 
         Ok(())
     }
 
-    pub fn create_warparty(
-        ctx : Context<CreateWarparty>,
-        _bump : u8,
-        _mints : Vec<Pubkey>
-    ) -> ProgramResult {
-        msg!("+ create warparty");
+    /// Unstakes a stake that is still inside its lock. `early_unlock_penalty_bps`
+    /// of the rewards earned at the lock rate are forfeited to the treasury and
+    /// the owner pays `early_unlock_fee` reward tokens on top. With an
+    /// unbonding period the stake goes through `request_unstake` first, which
+    /// takes the penalty, and leaves here once its cooldown has passed.
+    pub fn early_unstake<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, EarlyUnstake<'info>>,
+        ) -> ProgramResult {
+        msg!("+early unstake");
 
-        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
-        let warparty_data = &mut ctx.accounts.warparty_data;
-        let tier = &mut ctx.accounts.tier;
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
 
-        warparty_data.owner = *ctx.accounts.owner.key;
-        warparty_data.tier = tier.key();
-        warparty_data.rand = *ctx.accounts.rand.key;
-        warparty_data.active = true;
-        warparty_data.create_time = clock.unix_timestamp;
-        warparty_data.last_claim_time = 0;
-        warparty_data.nfts = _mints;
-        warparty_data.bump = _bump;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+        let nft_mint = &ctx.accounts.nft_mint;
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.user_reward_account.to_account_info().clone(),
-            to: ctx.accounts.pool_reward_account.to_account_info().clone(),
-            authority: ctx.accounts.owner.to_account_info().clone(),
+        if stake_data.unstaked {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        verify_owner_held(stake_data)?;
+        if !pool.early_unlock_enabled {
+            return Err(PoolError::EarlyUnlockDisabled.into());
+        }
+        if stake_data.soft {
+            msg!("Soft stakes are released with soft_unstake");
+            return Err(PoolError::InvalidStakeKind.into());
+        }
+        if !lock_active(pool, stake_data, clock.unix_timestamp)? {
+            msg!("Stake is not locked");
+            return Err(PoolError::InvalidTime.into());
+        }
+        verify_released(pool, stake_data, clock.unix_timestamp)?;
+        verify_nft_mint(nft_mint)?;
+        verify_metadata_account(&ctx.accounts.metadata, &nft_mint.key())?;
+
+        // `request_unstake` already booked an unbonding stake's rewards, less
+        // the penalty. Everything a shared-mode stake earns while locked
+        // counts as locked.
+        let (earned, locked_earned) = match pool.mode {
+            _ if stake_data.release_time != 0 => (0, 0),
+            PoolMode::Fixed => {
+                let breakdown = rewards::calculate_reward(
+                    &StakeSnapshot::from(&**stake_data),
+                    &CollectionSnapshot::from(&**collection_data),
+                    &PoolSnapshot::from(&**pool),
+                    clock.unix_timestamp,
+                )?;
+                (breakdown.total()?, breakdown.locked_reward)
+            }
+            PoolMode::Shared => {
+                pool.update_emission(clock.unix_timestamp)?;
+                let pending = rewards::pending_shared(stake_data.weight, pool.reward_per_weight, stake_data.reward_debt)?;
+                pool.set_stake_weight(stake_data, 0)?;
+                (pending, pending)
+            }
         };
+        let forfeited = rewards::mul_div(locked_earned, pool.early_unlock_penalty_bps, rewards::BPS)?;
+
+        let paid = pool.record_payout(collection_data, stake_data, earned - forfeited, ctx.accounts.pool_reward_account.amount)?;
+
+        pay_reward(
+            pool,
+            ctx.accounts.owner.key,
+            RewardAccounts {
+                pool_reward_account : ctx.accounts.pool_reward_account.to_account_info(),
+                user_reward_account : ctx.accounts.user_reward_account.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+            },
+            ctx.remaining_accounts,
+            paid,
+            clock.unix_timestamp,
+            ctx.program_id,
+        )?;
+
+        // Forfeited rewards simply stay put when the treasury is the vault.
+        if ctx.accounts.treasury_account.key() != ctx.accounts.pool_reward_account.key() {
+            let available = ctx.accounts.pool_reward_account.amount.saturating_sub(paid);
+            transfer_from_pool(
+                pool,
+                ctx.accounts.pool_reward_account.to_account_info(),
+                ctx.accounts.treasury_account.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+                forfeited.min(available),
+            )?;
+        }
 
-        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+        if pool.early_unlock_fee > 0 {
+            let cpi_accounts = Transfer {
+                from: ctx.accounts.user_reward_account.to_account_info().clone(),
+                to: ctx.accounts.treasury_account.to_account_info().clone(),
+                authority: ctx.accounts.owner.to_account_info().clone(),
+            };
 
-        let token_cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+            let cpi_program = ctx.accounts.token_program.to_account_info().clone();
 
-        token::transfer(token_cpi_ctx, tier.tokens)?;
+            let token_cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
 
-        tier.count = tier.count + 1;
+            token::transfer(token_cpi_ctx, pool.early_unlock_fee)?;
+        }
+
+        transfer_from_pool(
+            pool,
+            ctx.accounts.pool_nft_account.to_account_info(),
+            ctx.accounts.user_nft_account.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            1,
+        )?;
+
+        pool.close_stake(collection_data, &mut ctx.accounts.wallet, stake_data, clock.unix_timestamp)?;
 
         Ok(())
     }
 
-    pub fn remove_clan(
-        ctx : Context<RemoveClan>,
-    ) -> ProgramResult {
-        msg!("+ remove clan");
+    /// Starts the pool's unbonding cooldown. Rewards accrued so far are booked
+    /// as owed and the stake stops earning; once `release_time` has passed
+    /// the NFT leaves through `complete_unstake` (or `early_unstake`,
+    /// `soft_unstake` and `receipt_unstake` for those stakes). A position with a receipt expects the
+    /// holder's receipt token account as the first remaining account.
+    pub fn request_unstake(
+        ctx : Context<ChangeUnbonding>,
+        ) -> ProgramResult {
+        msg!("+request unstake");
 
-        let clan_data = &mut ctx.accounts.clan_data;
-        let tier_clan = &mut ctx.accounts.tier_clan;
-        
-        clan_data.active = false;
-        tier_clan.count = tier_clan.count - 1;
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+
+        verify_position_authority(stake_data, ctx.accounts.authority.key, ctx.remaining_accounts)?;
+        if stake_data.unstaked {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        if stake_data.release_time != 0 {
+            msg!("Stake is already unbonding");
+            return Err(PoolError::InvalidTime.into());
+        }
+        let early = lock_active(pool, stake_data, clock.unix_timestamp)? && *ctx.accounts.authority.key != pool.owner;
+        if early && (!pool.early_unlock_enabled || stake_data.soft || stake_data.receipt_mint != Pubkey::default()) {
+            msg!("Stake is locked");
+            return Err(PoolError::InvalidTime.into());
+        }
+
+        let locked_reward = if early && pool.mode == PoolMode::Fixed {
+            rewards::calculate_reward(
+                &StakeSnapshot::from(&**stake_data),
+                &CollectionSnapshot::from(&**collection_data),
+                &PoolSnapshot::from(&**pool),
+                clock.unix_timestamp,
+            )?.locked_reward
+        } else {
+            0
+        };
+        let reward = pool.settle_accrued(collection_data, stake_data, clock.unix_timestamp)?;
+        pool.set_stake_weight(stake_data, 0)?;
+
+        // An early request forfeits its penalty now, leaving it in the vault;
+        // `early_unstake` charges the fee once the cooldown is over.
+        let locked_earned = match pool.mode {
+            PoolMode::Fixed => locked_reward,
+            PoolMode::Shared if early => reward,
+            PoolMode::Shared => 0,
+        };
+        let forfeited = rewards::mul_div(locked_earned, pool.early_unlock_penalty_bps, rewards::BPS)?;
+        pool.record_payout(collection_data, stake_data, reward - forfeited, 0)?;
+        stake_data.early_penalty = forfeited;
+
+        stake_data.last_claim_time = clock.unix_timestamp;
+        stake_data.unbond_time = clock.unix_timestamp;
+        stake_data.release_time = clock.unix_timestamp
+            .checked_add(pool.unbonding_period)
+            .ok_or(PoolError::MathOverflow)?;
 
         Ok(())
     }
 
-    pub fn remove_company(
-        ctx : Context<RemoveCompany>,
-    ) -> ProgramResult {
-        msg!("+ remove company");
+    /// Calls off a pending unbonding; the stake earns again from now on.
+    pub fn cancel_unstake(
+        ctx : Context<ChangeUnbonding>,
+        ) -> ProgramResult {
+        msg!("+cancel unstake");
 
-        let company_data = &mut ctx.accounts.company_data;
-        let tier_company = &mut ctx.accounts.tier_company;
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
 
-        company_data.active = false;
-        tier_company.count = tier_company.count - 1;
+        verify_position_authority(stake_data, ctx.accounts.authority.key, ctx.remaining_accounts)?;
+        if stake_data.unstaked || stake_data.release_time == 0 {
+            msg!("Stake is not unbonding");
+            return Err(PoolError::InvalidTime.into());
+        }
+
+        stake_data.paused_time = clock.unix_timestamp
+            .checked_sub(stake_data.unbond_time)
+            .and_then(|paused| stake_data.paused_time.checked_add(paused))
+            .ok_or(PoolError::MathOverflow)?;
+        stake_data.last_claim_time = clock.unix_timestamp;
+        stake_data.unbond_time = 0;
+        stake_data.release_time = 0;
+
+        // The stake is back to staking, so an early request's penalty is
+        // owed to it again.
+        let refund = stake_data.early_penalty;
+        pool.record_payout(collection_data, stake_data, refund, 0)?;
+        stake_data.early_penalty = 0;
+
+        if pool.mode == PoolMode::Shared {
+            pool.update_emission(clock.unix_timestamp)?;
+            let weight = rewards::stake_weight(
+                &StakeSnapshot::from(&**stake_data),
+                &CollectionSnapshot::from(&**collection_data),
+                &PoolSnapshot::from(&**pool),
+                clock.unix_timestamp,
+            )?;
+            pool.set_stake_weight(stake_data, weight)?;
+        }
 
         Ok(())
     }
 
-    pub fn remove_warparty(
-        ctx : Context<RemoveWarparty>,
-    ) -> ProgramResult {
-        msg!("+ remove warparty");
-
-        let warparty_data = &mut ctx.accounts.warparty_data;
-        let tier_warparty = &mut ctx.accounts.tier_warparty;
+    /// Returns the NFT of a stake whose cooldown from `request_unstake` has
+    /// passed. Takes the same accounts as `unstake`, which it runs once it
+    /// has checked that the stake actually went through unbonding.
+    pub fn complete_unstake<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, Unstake<'info>>,
+        ) -> ProgramResult {
+        msg!("+complete unstake");
 
-        warparty_data.active = false;
-        tier_warparty.count = tier_warparty.count - 1;
+        if ctx.accounts.stake_data.release_time == 0 {
+            msg!("Request the unstake first");
+            return Err(PoolError::UnbondingRequired.into());
+        }
 
-        Ok(())
+        unstake(ctx)
     }
 
-    pub fn claim_clan(
-        ctx : Context<ClaimClan>
-    ) -> ProgramResult {
-        msg!("+ claim clan");
+    /// Settles a shared-mode stake whose lock has expired and drops its weight
+    /// to the unlocked rate. The weight is otherwise only refreshed when the
+    /// stake is touched, so anyone may call this to stop an expired lock
+    /// bonus from diluting the other stakers. What the stake earned is booked
+    /// as owed for its next claim.
+    pub fn refresh_weight(
+        ctx : Context<RefreshWeight>,
+        ) -> ProgramResult {
+        msg!("+refresh weight");
 
         let pool = &mut ctx.accounts.pool;
-        let tier_clan = &ctx.accounts.tier_clan;
-        let clan_data = &mut ctx.accounts.clan_data;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
         let clock = Clock::from_account_info(&ctx.accounts.clock)?;
-        
-        if clan_data.active == false {
-            return Err(PoolError::InvalidTier.into());
-        }
+        pool.status.verify_rewards()?;
 
-        if (clan_data.last_claim_time as u64 > tier_clan.claim_start) || (tier_clan.claim_start > clock.unix_timestamp as u64) {
+        if pool.mode != PoolMode::Shared {
+            return Err(PoolError::InvalidPoolMode.into());
+        }
+        if stake_data.unstaked {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        if stake_data.release_time != 0 || lock_active(pool, stake_data, clock.unix_timestamp)? {
+            msg!("Stake is not past its lock");
             return Err(PoolError::InvalidTime.into());
         }
 
-        let clan_amount = tier_clan.royalty * tier_clan.share as u64 / tier_clan.count / 10000;
+        let pending = pool.settle_accrued(collection_data, stake_data, clock.unix_timestamp)?;
+        pool.record_payout(collection_data, stake_data, pending, 0)?;
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_reward_account.to_account_info().clone(),
-            to: ctx.accounts.user_reward_account.to_account_info().clone(),
-            authority: pool.to_account_info().clone(),
-        };
-
-        let signer_seeds = &[
-            pool.rand.as_ref(),
-            &[pool.bump],
-        ];
+        Ok(())
+    }
 
-        let signer = &[&signer_seeds[..]];
+    /// Returns a staked NFT to `StakeData.owner` on the pool owner's
+    /// authority, ignoring locks and cooldowns. With `settle_rewards` the
+    /// accrued and owed rewards are paid to the owner as in `unstake`;
+    /// otherwise they are forfeited. Soft stakes are thawed in place, the
+    /// owner revokes the leftover delegation themselves; custodial stakes
+    /// ignore `edition` and soft stakes leave `pool_nft_account`, the pool's
+    /// empty token account for the mint, untouched. Positions held through a
+    /// receipt are left to `emergency_unstake`.
+    pub fn force_unstake<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, ForceUnstake<'info>>,
+        settle_rewards : bool
+        ) -> ProgramResult {
+        msg!("+force unstake");
 
-        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
 
-        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        if stake_data.unstaked {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        verify_owner_held(stake_data)?;
+
+        if settle_rewards {
+            let accrued = pool.settle_accrued(collection_data, stake_data, clock.unix_timestamp)?;
+            pool.set_stake_weight(stake_data, 0)?;
+            let paid = pool.record_payout(collection_data, stake_data, accrued, ctx.accounts.pool_reward_account.amount)?;
+
+            pay_reward(
+                pool,
+                &stake_data.owner,
+                RewardAccounts {
+                    pool_reward_account : ctx.accounts.pool_reward_account.to_account_info(),
+                    user_reward_account : ctx.accounts.user_reward_account.to_account_info(),
+                    token_program : ctx.accounts.token_program.to_account_info(),
+                },
+                ctx.remaining_accounts,
+                paid,
+                clock.unix_timestamp,
+                ctx.program_id,
+            )?;
+        } else {
+            pool.update_emission(clock.unix_timestamp)?;
+            pool.set_stake_weight(stake_data, 0)?;
+            pool.total_owed = pool.total_owed.checked_sub(stake_data.owed).ok_or(PoolError::MathOverflow)?;
+            stake_data.owed = 0;
+        }
 
-        token::transfer(token_cpi_ctx, clan_amount)?;
+        if stake_data.soft {
+            delegated_freeze(
+                pool,
+                THAW_DELEGATED_ACCOUNT,
+                SoftStakeAccounts {
+                    user_nft_account : ctx.accounts.user_nft_account.to_account_info(),
+                    edition : ctx.accounts.edition.clone(),
+                    nft_mint : ctx.accounts.nft_mint.to_account_info(),
+                    token_program : ctx.accounts.token_program.to_account_info(),
+                    token_metadata_program : ctx.accounts.token_metadata_program.clone(),
+                },
+            )?;
+        } else {
+            transfer_from_pool(
+                pool,
+                ctx.accounts.pool_nft_account.to_account_info(),
+                ctx.accounts.user_nft_account.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+                1,
+            )?;
+        }
 
-        clan_data.last_claim_time = clock.unix_timestamp;
+        pool.close_stake(collection_data, &mut ctx.accounts.wallet, stake_data, clock.unix_timestamp)?;
 
         Ok(())
     }
 
-    pub fn claim_company(
-        ctx : Context<ClaimCompany>
-    ) -> ProgramResult {
-        msg!("+ claim company");
+    /// Moves tokens that were sent to the pool outside of staking to
+    /// `destination_account`. Mints that could never be staked may be rescued
+    /// at any time. An NFT is returned to a wallet that staked it before and
+    /// has since unstaked: `stake_data` is the stake address of the mint for
+    /// the destination's owner and must hold a closed stake. Other NFTs wait
+    /// until the pool has no stakes left. The reward mint and any mint with a
+    /// `RewardStream` are never rescued, as their vaults hold users' rewards;
+    /// `stream` is that mint's stream address and must be unused.
+    pub fn rescue_nft(
+        ctx : Context<RescueNft>
+        ) -> ProgramResult {
+        msg!("+rescue nft");
 
+        let pool = &ctx.accounts.pool;
+        let nft_mint = &ctx.accounts.nft_mint;
+
+        let (stream_key, _) = Pubkey::find_program_address(
+            &[pool.key().as_ref(), nft_mint.key().as_ref()],
+            ctx.program_id,
+        );
+        if ctx.accounts.stream.key() != stream_key {
+            msg!("Not the stream address of this mint");
+            return Err(PoolError::InvalidRewardStream.into());
+        }
+        if nft_mint.key() == pool.reward_mint
+            || ctx.accounts.pool_nft_account.key() == pool.vesting_vault
+            || !ctx.accounts.stream.data_is_empty() {
+            msg!("Reward vaults cannot be rescued");
+            return Err(PoolError::InvalidTokenMint.into());
+        }
+
+        if verify_nft_mint(&ctx.accounts.nft_mint).is_ok() {
+            let (stake_key, _) = Pubkey::find_program_address(
+                &[nft_mint.key().as_ref(), ctx.accounts.destination_account.owner.as_ref(), pool.key().as_ref()],
+                ctx.program_id,
+            );
+            if ctx.accounts.stake_data.key() != stake_key {
+                msg!("Not the stake address of this mint");
+                return Err(PoolError::InvalidStakeData.into());
+            }
+            let unstaked = if ctx.accounts.stake_data.data_is_empty() {
+                false
+            } else {
+                let stake_data : Account<StakeData> = Account::try_from(&ctx.accounts.stake_data)?;
+                stake_data.unstaked
+            };
+            if !unstaked && pool.stats.staked_count != 0 {
+                msg!("NFT may still be staked");
+                return Err(PoolError::StakeDataInUse.into());
+            }
+        }
+
+        transfer_from_pool(
+            pool,
+            ctx.accounts.pool_nft_account.to_account_info(),
+            ctx.accounts.destination_account.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.pool_nft_account.amount,
+        )
+    }
+
+    pub fn claim<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, Claim<'info>>
+        ) -> ProgramResult {
         let pool = &mut ctx.accounts.pool;
-        let tier_company = &ctx.accounts.tier_company;
-        let company_data = &mut ctx.accounts.company_data;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
         let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+
+        if stake_data.unstaked && stake_data.owed == 0 {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        verify_owner_held(stake_data)?;
+        verify_metadata_account(&ctx.accounts.metadata, &stake_data.mint)?;
         
-        if company_data.active == false {
-            return Err(PoolError::InvalidTier.into());
+        // An unstaked position can only collect what it is still owed.
+        let mut total_reward = if stake_data.unstaked {
+            0
+        } else {
+            pool.settle_accrued(collection_data, stake_data, clock.unix_timestamp)?
+        };
+
+        if pool.mode == PoolMode::Fixed && *ctx.accounts.owner.key == pool.owner {
+            total_reward = total_reward.checked_mul(50).ok_or(PoolError::MathOverflow)?;
         }
 
-        if (company_data.last_claim_time as u64 > tier_company.claim_start) || (tier_company.claim_start > clock.unix_timestamp as u64) {
+        let paid = pool.record_payout(collection_data, stake_data, total_reward, ctx.accounts.pool_reward_account.amount)?;
+
+        pay_reward(
+            pool,
+            ctx.accounts.owner.key,
+            RewardAccounts {
+                pool_reward_account : ctx.accounts.pool_reward_account.to_account_info(),
+                user_reward_account : ctx.accounts.user_reward_account.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+            },
+            ctx.remaining_accounts,
+            paid,
+            clock.unix_timestamp,
+            ctx.program_id,
+        )?;
+
+        stake_data.last_claim_time = clock.unix_timestamp;
+
+        Ok(())
+    }
+
+    /// Pays out what the stake has earned so far and starts a new lock of
+    /// `lock_period` from now. A stake still inside its lock can only move to
+    /// a longer lock option.
+    pub fn relock<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, Relock<'info>>,
+        lock_period : u64
+        ) -> ProgramResult {
+        msg!("+relock");
+
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+
+        if stake_data.unstaked {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        verify_owner_held(stake_data)?;
+        if stake_data.release_time != 0 {
+            msg!("Stake is unbonding");
             return Err(PoolError::InvalidTime.into());
         }
+        if collection_data.find_lock_option(lock_period).is_none() {
+            msg!("Lock period is not offered by this collection");
+            return Err(PoolError::InvalidLockPeriod.into());
+        }
+        let lock_end = rewards::lock_end(&StakeSnapshot::from(&**stake_data), &PoolSnapshot::from(&**pool))?;
+        if lock_end.map_or(false, |end| clock.unix_timestamp <= end) && lock_period <= stake_data.lock_period {
+            msg!("A running lock can only be extended");
+            return Err(PoolError::InvalidLockPeriod.into());
+        }
+        verify_metadata_account(&ctx.accounts.metadata, &stake_data.mint)?;
+
+        let total_reward = pool.settle_accrued(collection_data, stake_data, clock.unix_timestamp)?;
+        let paid = pool.record_payout(collection_data, stake_data, total_reward, ctx.accounts.pool_reward_account.amount)?;
+
+        pay_reward(
+            pool,
+            ctx.accounts.owner.key,
+            RewardAccounts {
+                pool_reward_account : ctx.accounts.pool_reward_account.to_account_info(),
+                user_reward_account : ctx.accounts.user_reward_account.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+            },
+            ctx.remaining_accounts,
+            paid,
+            clock.unix_timestamp,
+            ctx.program_id,
+        )?;
 
-        let clan_amount = tier_company.royalty * tier_company.share as u64 / tier_company.count / 10000;
-
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_reward_account.to_account_info().clone(),
-            to: ctx.accounts.user_reward_account.to_account_info().clone(),
-            authority: pool.to_account_info().clone(),
-        };
+        stake_data.last_claim_time = clock.unix_timestamp;
+        if stake_data.locked {
+            pool.stats.remove_lock()?;
+            collection_data.remove_lock(stake_data.lock_period)?;
+        }
+        collection_data.check_lock_capacity(lock_period)?;
+        pool.stats.add_lock()?;
+        collection_data.add_lock(lock_period)?;
+        stake_data.locked = true;
+        stake_data.lock_period = lock_period;
+        stake_data.lock_start = clock.unix_timestamp;
+
+        if pool.mode == PoolMode::Shared {
+            let weight = rewards::stake_weight(
+                &StakeSnapshot::from(&**stake_data),
+                &CollectionSnapshot::from(&**collection_data),
+                &PoolSnapshot::from(&**pool),
+                clock.unix_timestamp,
+            )?;
+            pool.set_stake_weight(stake_data, weight)?;
+        }
 
-        let signer_seeds = &[
-            pool.rand.as_ref(),
-            &[pool.bump],
-        ];
+        Ok(())
+    }
 
-        let signer = &[&signer_seeds[..]];
+    /// Stakes several NFTs of one collection. `remaining_accounts` holds one
+    /// group of `STAKE_BATCH_GROUP` accounts per entry of `terms`:
+    /// `[stake_data, nft_mint, metadata, edition, user_nft_account, pool_nft_account]`.
+    pub fn stake_batch<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, StakeBatch<'info>>,
+        terms : Vec<StakeTerms>
+        ) -> ProgramResult {
+        msg!("+stake batch");
 
-        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let owner = ctx.accounts.owner.key();
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
 
-        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        if ctx.remaining_accounts.len() != terms.len() * STAKE_BATCH_GROUP {
+            return Err(PoolError::InvalidBatch.into());
+        }
 
-        token::transfer(token_cpi_ctx, clan_amount)?;
+        for (group, terms) in ctx.remaining_accounts.chunks(STAKE_BATCH_GROUP).zip(terms.iter()) {
+            let mut stake_data : Account<StakeData> = Account::try_from(&group[0])?;
+            let nft_mint : Account<Mint> = Account::try_from(&group[1])?;
+            let user_nft_account : Account<TokenAccount> = Account::try_from(&group[4])?;
+            let pool_nft_account : Account<TokenAccount> = Account::try_from(&group[5])?;
+
+            if stake_data.owner != owner
+                || stake_data.pool != pool.key()
+                || stake_data.mint != nft_mint.key()
+                || user_nft_account.owner != owner
+                || user_nft_account.mint != nft_mint.key()
+                || pool_nft_account.owner != pool.key()
+                || pool_nft_account.mint != nft_mint.key() {
+                return Err(PoolError::InvalidBatch.into());
+            }
 
-        company_data.last_claim_time = clock.unix_timestamp;
+            let multiplier_bps = check_stake(
+                pool,
+                collection_data,
+                &group[2],
+                &group[3],
+                &nft_mint,
+                terms.locked,
+                terms.lock_period,
+                terms.rarity_multiplier_bps,
+                &terms.rarity_proof,
+                clock.unix_timestamp,
+            )?;
+
+            let cpi_accounts = Transfer {
+                from: group[4].clone(),
+                to: group[5].clone(),
+                authority: ctx.accounts.owner.to_account_info().clone(),
+            };
+
+            let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+
+            let token_cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+            token::transfer(token_cpi_ctx, 1)?;
+
+            open_stake(
+                pool,
+                collection_data,
+                &mut ctx.accounts.wallet,
+                &mut stake_data,
+                terms.locked,
+                terms.lock_period,
+                multiplier_bps,
+                false,
+                clock.unix_timestamp,
+            )?;
+            stake_data.exit(ctx.program_id)?;
+        }
 
         Ok(())
     }
 
-    pub fn claim_warparty(
-        ctx : Context<ClaimWarparty>
-    ) -> ProgramResult {
-        msg!("+ claim warparty");
+    /// Unstakes several NFTs of one collection and pays their rewards in a
+    /// single transfer. `remaining_accounts` holds the vesting accounts
+    /// `pay_reward` expects, if any, followed by one group of
+    /// `UNSTAKE_BATCH_GROUP` accounts per NFT, laid out as in `stake_batch`
+    /// without the edition.
+    pub fn unstake_batch<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, UnstakeBatch<'info>>,
+        ) -> ProgramResult {
+        msg!("+unstake batch");
 
         let pool = &mut ctx.accounts.pool;
-        let tier_warparty = &ctx.accounts.tier_warparty;
-        let warparty_data = &mut ctx.accounts.warparty_data;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let owner = ctx.accounts.owner.key();
         let clock = Clock::from_account_info(&ctx.accounts.clock)?;
-        
-        if warparty_data.active == false {
-            return Err(PoolError::InvalidTier.into());
+        pool.status.verify_rewards()?;
+
+        let (vesting_accounts, groups) = split_vesting_accounts(pool, ctx.remaining_accounts);
+        if groups.len() % UNSTAKE_BATCH_GROUP != 0 {
+            return Err(PoolError::InvalidBatch.into());
         }
 
-        if (warparty_data.last_claim_time as u64 > tier_warparty.claim_start) || (tier_warparty.claim_start > clock.unix_timestamp as u64) {
-            return Err(PoolError::InvalidTime.into());
+        let mut available = ctx.accounts.pool_reward_account.amount;
+        let mut total_paid : u64 = 0;
+
+        for group in groups.chunks(UNSTAKE_BATCH_GROUP) {
+            let mut stake_data : Account<StakeData> = Account::try_from(&group[0])?;
+            let nft_mint : Account<Mint> = Account::try_from(&group[1])?;
+            let user_nft_account : Account<TokenAccount> = Account::try_from(&group[3])?;
+            let pool_nft_account : Account<TokenAccount> = Account::try_from(&group[4])?;
+
+            if stake_data.owner != owner
+                || stake_data.pool != pool.key()
+                || stake_data.collection != collection_data.key()
+                || stake_data.mint != nft_mint.key()
+                || user_nft_account.owner != owner
+                || user_nft_account.mint != nft_mint.key()
+                || pool_nft_account.owner != pool.key()
+                || pool_nft_account.mint != nft_mint.key() {
+                return Err(PoolError::InvalidBatch.into());
+            }
+            if stake_data.unstaked {
+                return Err(PoolError::AlreadyUnstaked.into());
+            }
+            verify_owner_held(&stake_data)?;
+            verify_released(pool, &stake_data, clock.unix_timestamp)?;
+            if stake_data.soft {
+                msg!("Soft stakes are released with soft_unstake");
+                return Err(PoolError::InvalidStakeKind.into());
+            }
+            if lock_active(pool, &stake_data, clock.unix_timestamp)? && owner != pool.owner {
+                return Err(PoolError::InvalidTime.into());
+            }
+            verify_nft_mint(&nft_mint)?;
+            verify_metadata_account(&group[2], &nft_mint.key())?;
+
+            let mut reward = pool.settle_accrued(collection_data, &mut stake_data, clock.unix_timestamp)?;
+            pool.set_stake_weight(&mut stake_data, 0)?;
+
+            if pool.mode == PoolMode::Fixed && owner == pool.owner {
+                reward = reward.checked_mul(20).ok_or(PoolError::MathOverflow)?;
+            }
+
+            let paid = pool.record_payout(collection_data, &mut stake_data, reward, available)?;
+            available -= paid;
+            total_paid = total_paid.checked_add(paid).ok_or(PoolError::MathOverflow)?;
+
+            transfer_from_pool(
+                pool,
+                group[4].clone(),
+                group[3].clone(),
+                ctx.accounts.token_program.to_account_info(),
+                1,
+            )?;
+
+            pool.close_stake(collection_data, &mut ctx.accounts.wallet, &mut stake_data, clock.unix_timestamp)?;
+            stake_data.exit(ctx.program_id)?;
         }
 
-        let clan_amount = tier_warparty.royalty * tier_warparty.share as u64 / tier_warparty.count / 10000;
+        pay_reward(
+            pool,
+            ctx.accounts.owner.key,
+            RewardAccounts {
+                pool_reward_account : ctx.accounts.pool_reward_account.to_account_info(),
+                user_reward_account : ctx.accounts.user_reward_account.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+            },
+            vesting_accounts,
+            total_paid,
+            clock.unix_timestamp,
+            ctx.program_id,
+        )
+    }
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_reward_account.to_account_info().clone(),
-            to: ctx.accounts.user_reward_account.to_account_info().clone(),
-            authority: pool.to_account_info().clone(),
+    /// Claims for several stakes of one collection in a single transfer.
+    /// `remaining_accounts` holds the vesting accounts `pay_reward` expects,
+    /// if any, followed by one `[stake_data, metadata]` group per stake.
+    pub fn claim_batch<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, ClaimBatch<'info>>,
+        ) -> ProgramResult {
+        msg!("+claim batch");
+
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let owner = ctx.accounts.owner.key();
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+
+        let (vesting_accounts, groups) = split_vesting_accounts(pool, ctx.remaining_accounts);
+        if groups.len() % CLAIM_BATCH_GROUP != 0 {
+            return Err(PoolError::InvalidBatch.into());
+        }
+
+        let mut available = ctx.accounts.pool_reward_account.amount;
+        let mut total_paid : u64 = 0;
+
+        for group in groups.chunks(CLAIM_BATCH_GROUP) {
+            let mut stake_data : Account<StakeData> = Account::try_from(&group[0])?;
+
+            if stake_data.owner != owner
+                || stake_data.pool != pool.key()
+                || stake_data.collection != collection_data.key() {
+                return Err(PoolError::InvalidBatch.into());
+            }
+            if stake_data.unstaked && stake_data.owed == 0 {
+                return Err(PoolError::AlreadyUnstaked.into());
+            }
+            verify_owner_held(&stake_data)?;
+            verify_metadata_account(&group[1], &stake_data.mint)?;
+
+            let mut reward = if stake_data.unstaked {
+                0
+            } else {
+                pool.settle_accrued(collection_data, &mut stake_data, clock.unix_timestamp)?
+            };
+
+            if pool.mode == PoolMode::Fixed && owner == pool.owner {
+                reward = reward.checked_mul(50).ok_or(PoolError::MathOverflow)?;
+            }
+
+            let paid = pool.record_payout(collection_data, &mut stake_data, reward, available)?;
+            available -= paid;
+            total_paid = total_paid.checked_add(paid).ok_or(PoolError::MathOverflow)?;
+
+            stake_data.last_claim_time = clock.unix_timestamp;
+            stake_data.exit(ctx.program_id)?;
+        }
+
+        pay_reward(
+            pool,
+            ctx.accounts.owner.key,
+            RewardAccounts {
+                pool_reward_account : ctx.accounts.pool_reward_account.to_account_info(),
+                user_reward_account : ctx.accounts.user_reward_account.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+            },
+            vesting_accounts,
+            total_paid,
+            clock.unix_timestamp,
+            ctx.program_id,
+        )
+    }
+
+    /// Mints a 1-of-1 receipt token for a custodial stake. From then on the
+    /// position is claimed and unstaked by whoever holds the receipt, with
+    /// `receipt_claim` and `receipt_unstake`; the owner's own instructions
+    /// no longer accept it. New stakes can get theirs from `stake` instead.
+    pub fn issue_receipt(
+        ctx : Context<IssueReceipt>,
+        ) -> ProgramResult {
+        msg!("+issue receipt");
+
+        let pool = &ctx.accounts.pool;
+        let stake_data = &mut ctx.accounts.stake_data;
+
+        if stake_data.unstaked {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        if stake_data.soft {
+            msg!("Soft stakes stay with their owner");
+            return Err(PoolError::InvalidStakeKind.into());
+        }
+        verify_owner_held(stake_data)?;
+
+        mint_receipt(
+            pool,
+            stake_data,
+            ctx.accounts.receipt_mint.to_account_info(),
+            ctx.accounts.receipt_account.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+        )
+    }
+
+    /// `claim` for the holder of a position's receipt. Once an unstaked
+    /// position has been paid everything it is owed, the receipt is burned.
+    pub fn receipt_claim<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, ReceiptClaim<'info>>
+        ) -> ProgramResult {
+        msg!("+receipt claim");
+
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+
+        if stake_data.unstaked && stake_data.owed == 0 {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        verify_metadata_account(&ctx.accounts.metadata, &stake_data.mint)?;
+
+        let mut total_reward = if stake_data.unstaked {
+            0
+        } else {
+            pool.settle_accrued(collection_data, stake_data, clock.unix_timestamp)?
         };
 
-        let signer_seeds = &[
-            pool.rand.as_ref(),
-            &[pool.bump],
-        ];
+        if pool.mode == PoolMode::Fixed && *ctx.accounts.holder.key == pool.owner {
+            total_reward = total_reward.checked_mul(50).ok_or(PoolError::MathOverflow)?;
+        }
 
-        let signer = &[&signer_seeds[..]];
+        let paid = pool.record_payout(collection_data, stake_data, total_reward, ctx.accounts.pool_reward_account.amount)?;
+
+        pay_reward(
+            pool,
+            ctx.accounts.holder.key,
+            RewardAccounts {
+                pool_reward_account : ctx.accounts.pool_reward_account.to_account_info(),
+                user_reward_account : ctx.accounts.user_reward_account.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+            },
+            ctx.remaining_accounts,
+            paid,
+            clock.unix_timestamp,
+            ctx.program_id,
+        )?;
 
-        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+        stake_data.last_claim_time = clock.unix_timestamp;
 
-        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        retire_receipt(
+            stake_data,
+            ctx.accounts.receipt_mint.to_account_info(),
+            ctx.accounts.receipt_account.to_account_info(),
+            ctx.accounts.holder.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+        )
+    }
 
-        token::transfer(token_cpi_ctx, clan_amount)?;
+    /// `unstake` for the holder of a position's receipt: the NFT and rewards
+    /// go to the holder, and the receipt is burned unless rewards are still
+    /// owed.
+    pub fn receipt_unstake<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, ReceiptUnstake<'info>>,
+        ) -> ProgramResult {
+        msg!("+receipt unstake");
 
-        warparty_data.last_claim_time = clock.unix_timestamp;
+        let pool = &mut ctx.accounts.pool;
+        let collection_data = &mut ctx.accounts.collection_data;
+        let stake_data = &mut ctx.accounts.stake_data;
+
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+        let nft_mint = &ctx.accounts.nft_mint;
+
+        if stake_data.unstaked {
+            return Err(PoolError::AlreadyUnstaked.into());
+        }
+        verify_released(pool, stake_data, clock.unix_timestamp)?;
+        if lock_active(pool, stake_data, clock.unix_timestamp)? && *ctx.accounts.holder.key != pool.owner {
+            return Err(PoolError::InvalidTime.into());
+        }
+        verify_nft_mint(nft_mint)?;
+        verify_metadata_account(&ctx.accounts.metadata, &nft_mint.key())?;
+
+        let mut total_reward = pool.settle_accrued(collection_data, stake_data, clock.unix_timestamp)?;
+        pool.set_stake_weight(stake_data, 0)?;
+
+        if pool.mode == PoolMode::Fixed && *ctx.accounts.holder.key == pool.owner {
+            total_reward = total_reward.checked_mul(20).ok_or(PoolError::MathOverflow)?;
+        }
+
+        let paid = pool.record_payout(collection_data, stake_data, total_reward, ctx.accounts.pool_reward_account.amount)?;
+
+        pay_reward(
+            pool,
+            ctx.accounts.holder.key,
+            RewardAccounts {
+                pool_reward_account : ctx.accounts.pool_reward_account.to_account_info(),
+                user_reward_account : ctx.accounts.user_reward_account.to_account_info(),
+                token_program : ctx.accounts.token_program.to_account_info(),
+            },
+            ctx.remaining_accounts,
+            paid,
+            clock.unix_timestamp,
+            ctx.program_id,
+        )?;
+
+        transfer_from_pool(
+            pool,
+            ctx.accounts.pool_nft_account.to_account_info(),
+            ctx.accounts.user_nft_account.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            1,
+        )?;
+
+        pool.close_stake(collection_data, &mut ctx.accounts.wallet, stake_data, clock.unix_timestamp)?;
+
+        retire_receipt(
+            stake_data,
+            ctx.accounts.receipt_mint.to_account_info(),
+            ctx.accounts.receipt_account.to_account_info(),
+            ctx.accounts.holder.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+        )
+    }
+
+    pub fn init_reward_stream(
+        ctx : Context<InitRewardStream>,
+        _bump : u8,
+        _rates : Vec<StreamRate>
+        ) -> ProgramResult {
+        msg!("+ init reward stream");
+
+        if _rates.len() > MAX_STREAM_RATES {
+            return Err(PoolError::InvalidRewardStream.into());
+        }
+
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        let stream = &mut ctx.accounts.stream;
+
+        stream.pool = ctx.accounts.pool.key();
+        stream.reward_mint = ctx.accounts.reward_mint.key();
+        stream.vault = ctx.accounts.stream_vault.key();
+        stream.start_time = clock.unix_timestamp;
+        stream.rates = _rates;
+        stream.bump = _bump;
 
         Ok(())
     }
 
-    pub fn set_royalty(
-        ctx : Context<SetRoyalty>,
-        _royalty : u64,
-        _start_time : u64
-    ) -> ProgramResult {
-        msg!("+ set royalty");
+    pub fn update_reward_stream(
+        ctx : Context<UpdateRewardStream>,
+        _rates : Vec<StreamRate>
+        ) -> ProgramResult {
+        msg!("+ update reward stream");
 
-        let tier_clan = &mut ctx.accounts.tier_clan;
-        let tier_company = &mut ctx.accounts.tier_company;
-        let tier_warparty = &mut ctx.accounts.tier_warparty;
+        if _rates.len() > MAX_STREAM_RATES {
+            return Err(PoolError::InvalidRewardStream.into());
+        }
 
-        tier_clan.royalty = _royalty;
-        tier_clan.claim_start = _start_time;
+        ctx.accounts.stream.rates = _rates;
 
-        tier_company.royalty = _royalty;
-        tier_company.claim_start = _start_time;
+        Ok(())
+    }
 
-        tier_warparty.royalty = _royalty;
-        tier_warparty.claim_start = _start_time;
+    pub fn init_stake_stream(
+        ctx : Context<InitStakeStream>,
+        _bump : u8
+        ) -> ProgramResult {
+        msg!("+ init stake stream");
+
+        let stake_data = &ctx.accounts.stake_data;
+        let stake_stream = &mut ctx.accounts.stake_stream;
+
+        stake_stream.stake_data = stake_data.key();
+        stake_stream.stream = ctx.accounts.stream.key();
+        stake_stream.stake_time = stake_data.stake_time;
+        stake_stream.last_claim_time = stake_data.stake_time;
+        stake_stream.bump = _bump;
+        stake_stream.paused_time = 0;
+
+        Ok(())
+    }
+
+    /// Claims one reward stream for a stake. A position with a receipt pays its
+    /// holder, who passes their receipt token account as the first remaining
+    /// account; holders should claim before the receipt is retired.
+    pub fn claim_stream(
+        ctx : Context<ClaimStream>
+        ) -> ProgramResult {
+        msg!("+ claim stream");
+
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        ctx.accounts.pool.status.verify_rewards()?;
+        verify_position_authority(&ctx.accounts.stake_data, ctx.accounts.authority.key, ctx.remaining_accounts)?;
+        let amount = ctx.accounts.stake_stream.settle(
+            &ctx.accounts.pool,
+            &ctx.accounts.stake_data,
+            &ctx.accounts.stream,
+            clock.unix_timestamp,
+        )?;
+
+        transfer_from_pool(
+            &ctx.accounts.pool,
+            ctx.accounts.stream_vault.to_account_info(),
+            ctx.accounts.user_stream_account.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            amount,
+        )
+    }
+
+    /// Claims several reward streams for one stake. `remaining_accounts` holds
+    /// one `[stream, stake_stream, stream_vault, user_stream_account]` group
+    /// per stream, after the receipt token account for a position with a
+    /// receipt.
+    pub fn claim_streams<'a, 'b, 'c, 'info>(
+        ctx : Context<'a, 'b, 'c, 'info, ClaimStreams<'info>>
+        ) -> ProgramResult {
+        msg!("+ claim streams");
+
+        let pool = &ctx.accounts.pool;
+        let stake_data = &ctx.accounts.stake_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+
+        verify_position_authority(stake_data, ctx.accounts.authority.key, ctx.remaining_accounts)?;
+        let receipt_accounts = if stake_data.receipt_mint == Pubkey::default() { 0 } else { 1 };
+        let groups = &ctx.remaining_accounts[receipt_accounts..];
+
+        if groups.len() % 4 != 0 {
+            return Err(PoolError::InvalidRewardStream.into());
+        }
+
+        for group in groups.chunks(4) {
+            let stream : Account<RewardStream> = Account::try_from(&group[0])?;
+            let mut stake_stream : Account<StakeStream> = Account::try_from(&group[1])?;
+            let stream_vault : Account<TokenAccount> = Account::try_from(&group[2])?;
+            let user_stream_account : Account<TokenAccount> = Account::try_from(&group[3])?;
+
+            if stream.pool != pool.key()
+                || stake_stream.stake_data != stake_data.key()
+                || stake_stream.stream != stream.key()
+                || stream_vault.key() != stream.vault
+                || user_stream_account.owner != ctx.accounts.authority.key()
+                || user_stream_account.mint != stream.reward_mint {
+                return Err(PoolError::InvalidRewardStream.into());
+            }
+
+            let amount = stake_stream.settle(pool, stake_data, &stream, clock.unix_timestamp)?;
+            stake_stream.exit(ctx.program_id)?;
+
+            transfer_from_pool(
+                pool,
+                group[2].clone(),
+                group[3].clone(),
+                ctx.accounts.token_program.to_account_info(),
+                amount,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_vesting(
+        ctx : Context<SetVesting>,
+        _cliff : i64,
+        _duration : i64
+        ) -> ProgramResult {
+        msg!("+ set vesting");
+
+        if _cliff < 0 || _duration < 0 {
+            return Err(PoolError::InvalidTime.into());
+        }
+
+        let pool = &mut ctx.accounts.pool;
+
+        pool.vesting_cliff = _cliff;
+        pool.vesting_duration = _duration;
+        pool.vesting_vault = ctx.accounts.vesting_vault.key();
+
+        Ok(())
+    }
+
+    pub fn init_vesting(
+        ctx : Context<InitVesting>,
+        _bump : u8
+        ) -> ProgramResult {
+        msg!("+ init vesting");
+
+        let vesting = &mut ctx.accounts.vesting;
+
+        vesting.owner = *ctx.accounts.owner.key;
+        vesting.pool = ctx.accounts.pool.key();
+        vesting.vested_balance = 0;
+        vesting.tranches = Vec::new();
+        vesting.total_deposited = 0;
+        vesting.total_released = 0;
+        vesting.bump = _bump;
+
+        Ok(())
+    }
+
+    pub fn release_vested(
+        ctx : Context<ReleaseVested>
+        ) -> ProgramResult {
+        msg!("+ release vested");
+
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        ctx.accounts.pool.status.verify_rewards()?;
+        let amount = ctx.accounts.vesting.release(clock.unix_timestamp)?;
+
+        transfer_from_pool(
+            &ctx.accounts.pool,
+            ctx.accounts.vesting_vault.to_account_info(),
+            ctx.accounts.user_reward_account.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            amount,
+        )
+    }
+
+    pub fn init_tier(
+        ctx : Context<InitTier>,
+        _bump : u8,
+        _seed :  String,
+        _share : u32,
+        _points : u8,
+        _tokens : u64,
+        ) -> ProgramResult {
+
+        msg!("+ Init tier");
+
+        let tier = &mut ctx.accounts.tier;
+
+        tier.owner = *ctx.accounts.owner.key;
+        tier.pool = ctx.accounts.pool.key();
+        tier.share = _share;
+        tier.points = _points;
+        tier.tokens = _tokens;
+        tier.count = 0;
+        tier.royalty = 0;
+        tier.claim_start = 0;
+        tier.seed = _seed;
+        tier.bump = _bump;
+
+        Ok(())
+    }
+    
+    pub fn create_clan(
+        ctx : Context<CreateClan>,
+        _bump : u8,
+        _mints : Vec<Pubkey>
+    ) -> ProgramResult {
+        msg!("+ create clan");
+
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        ctx.accounts.pool.status.verify_staking()?;
+        let clan_data = &mut ctx.accounts.clan_data;
+        let tier = &mut ctx.accounts.tier;
+
+        clan_data.owner = *ctx.accounts.owner.key;
+        clan_data.tier = tier.key();
+        clan_data.rand = *ctx.accounts.rand.key;
+        clan_data.active = true;
+        clan_data.create_time = clock.unix_timestamp;
+        clan_data.last_claim_time = 0;
+        clan_data.nfts = _mints;
+        clan_data.bump = _bump;
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.user_reward_account.to_account_info().clone(),
+            to: ctx.accounts.pool_reward_account.to_account_info().clone(),
+            authority: ctx.accounts.owner.to_account_info().clone(),
+        };
+
+        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+
+        let token_cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+        token::transfer(token_cpi_ctx, tier.tokens)?;
+
+        tier.count = tier.count + 1;
+
+        Ok(())
+    }
+
+    pub fn create_company(
+        ctx : Context<CreateCompany>,
+        _bump : u8,
+        _mints : Vec<Pubkey>
+    ) -> ProgramResult {
+        msg!("+ create company");
+
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        ctx.accounts.pool.status.verify_staking()?;
+        let company_data = &mut ctx.accounts.company_data;
+        let tier = &mut ctx.accounts.tier;
+
+        company_data.owner = *ctx.accounts.owner.key;
+        company_data.tier = tier.key();
+        company_data.rand = *ctx.accounts.rand.key;
+        company_data.active = true;
+        company_data.create_time = clock.unix_timestamp;
+        company_data.last_claim_time = 0;
+        company_data.nfts = _mints;
+        company_data.bump = _bump;
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.user_reward_account.to_account_info().clone(),
+            to: ctx.accounts.pool_reward_account.to_account_info().clone(),
+            authority: ctx.accounts.owner.to_account_info().clone(),
+        };
+
+        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+
+        let token_cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+        token::transfer(token_cpi_ctx, tier.tokens)?;
+
+        tier.count = tier.count + 1;
+
+        Ok(())
+    }
+
+    pub fn create_warparty(
+        ctx : Context<CreateWarparty>,
+        _bump : u8,
+        _mints : Vec<Pubkey>
+    ) -> ProgramResult {
+        msg!("+ create warparty");
+
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        ctx.accounts.pool.status.verify_staking()?;
+        let warparty_data = &mut ctx.accounts.warparty_data;
+        let tier = &mut ctx.accounts.tier;
+
+        warparty_data.owner = *ctx.accounts.owner.key;
+        warparty_data.tier = tier.key();
+        warparty_data.rand = *ctx.accounts.rand.key;
+        warparty_data.active = true;
+        warparty_data.create_time = clock.unix_timestamp;
+        warparty_data.last_claim_time = 0;
+        warparty_data.nfts = _mints;
+        warparty_data.bump = _bump;
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.user_reward_account.to_account_info().clone(),
+            to: ctx.accounts.pool_reward_account.to_account_info().clone(),
+            authority: ctx.accounts.owner.to_account_info().clone(),
+        };
+
+        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+
+        let token_cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+
+        token::transfer(token_cpi_ctx, tier.tokens)?;
+
+        tier.count = tier.count + 1;
+
+        Ok(())
+    }
+
+    pub fn remove_clan(
+        ctx : Context<RemoveClan>,
+    ) -> ProgramResult {
+        msg!("+ remove clan");
+
+        let clan_data = &mut ctx.accounts.clan_data;
+        let tier_clan = &mut ctx.accounts.tier_clan;
+        
+        clan_data.active = false;
+        tier_clan.count = tier_clan.count - 1;
+
+        Ok(())
+    }
+
+    pub fn remove_company(
+        ctx : Context<RemoveCompany>,
+    ) -> ProgramResult {
+        msg!("+ remove company");
+
+        let company_data = &mut ctx.accounts.company_data;
+        let tier_company = &mut ctx.accounts.tier_company;
+
+        company_data.active = false;
+        tier_company.count = tier_company.count - 1;
+
+        Ok(())
+    }
+
+    pub fn remove_warparty(
+        ctx : Context<RemoveWarparty>,
+    ) -> ProgramResult {
+        msg!("+ remove warparty");
+
+        let warparty_data = &mut ctx.accounts.warparty_data;
+        let tier_warparty = &mut ctx.accounts.tier_warparty;
+
+        warparty_data.active = false;
+        tier_warparty.count = tier_warparty.count - 1;
+
+        Ok(())
+    }
+
+    pub fn claim_clan(
+        ctx : Context<ClaimClan>
+    ) -> ProgramResult {
+        msg!("+ claim clan");
+
+        let pool = &mut ctx.accounts.pool;
+        let tier_clan = &ctx.accounts.tier_clan;
+        let clan_data = &mut ctx.accounts.clan_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+        
+        if clan_data.active == false {
+            return Err(PoolError::InvalidTier.into());
+        }
+
+        if (clan_data.last_claim_time as u64 > tier_clan.claim_start) || (tier_clan.claim_start > clock.unix_timestamp as u64) {
+            return Err(PoolError::InvalidTime.into());
+        }
+
+        let clan_amount = rewards::group_share(tier_clan.royalty, tier_clan.share, tier_clan.count)?;
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.pool_reward_account.to_account_info().clone(),
+            to: ctx.accounts.user_reward_account.to_account_info().clone(),
+            authority: pool.to_account_info().clone(),
+        };
+
+        let signer_seeds = &[
+            pool.rand.as_ref(),
+            &[pool.bump],
+        ];
+
+        let signer = &[&signer_seeds[..]];
+
+        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+
+        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+
+        token::transfer(token_cpi_ctx, clan_amount)?;
+
+        clan_data.last_claim_time = clock.unix_timestamp;
+
+        Ok(())
+    }
+
+    pub fn claim_company(
+        ctx : Context<ClaimCompany>
+    ) -> ProgramResult {
+        msg!("+ claim company");
+
+        let pool = &mut ctx.accounts.pool;
+        let tier_company = &ctx.accounts.tier_company;
+        let company_data = &mut ctx.accounts.company_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+        
+        if company_data.active == false {
+            return Err(PoolError::InvalidTier.into());
+        }
+
+        if (company_data.last_claim_time as u64 > tier_company.claim_start) || (tier_company.claim_start > clock.unix_timestamp as u64) {
+            return Err(PoolError::InvalidTime.into());
+        }
+
+        let clan_amount = rewards::group_share(tier_company.royalty, tier_company.share, tier_company.count)?;
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.pool_reward_account.to_account_info().clone(),
+            to: ctx.accounts.user_reward_account.to_account_info().clone(),
+            authority: pool.to_account_info().clone(),
+        };
+
+        let signer_seeds = &[
+            pool.rand.as_ref(),
+            &[pool.bump],
+        ];
+
+        let signer = &[&signer_seeds[..]];
+
+        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+
+        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+
+        token::transfer(token_cpi_ctx, clan_amount)?;
+
+        company_data.last_claim_time = clock.unix_timestamp;
+
+        Ok(())
+    }
+
+    pub fn claim_warparty(
+        ctx : Context<ClaimWarparty>
+    ) -> ProgramResult {
+        msg!("+ claim warparty");
+
+        let pool = &mut ctx.accounts.pool;
+        let tier_warparty = &ctx.accounts.tier_warparty;
+        let warparty_data = &mut ctx.accounts.warparty_data;
+        let clock = Clock::from_account_info(&ctx.accounts.clock)?;
+        pool.status.verify_rewards()?;
+        
+        if warparty_data.active == false {
+            return Err(PoolError::InvalidTier.into());
+        }
+
+        if (warparty_data.last_claim_time as u64 > tier_warparty.claim_start) || (tier_warparty.claim_start > clock.unix_timestamp as u64) {
+            return Err(PoolError::InvalidTime.into());
+        }
+
+        let clan_amount = rewards::group_share(tier_warparty.royalty, tier_warparty.share, tier_warparty.count)?;
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.pool_reward_account.to_account_info().clone(),
+            to: ctx.accounts.user_reward_account.to_account_info().clone(),
+            authority: pool.to_account_info().clone(),
+        };
+
+        let signer_seeds = &[
+            pool.rand.as_ref(),
+            &[pool.bump],
+        ];
+
+        let signer = &[&signer_seeds[..]];
+
+        let cpi_program = ctx.accounts.token_program.to_account_info().clone();
+
+        let token_cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+
+        token::transfer(token_cpi_ctx, clan_amount)?;
+
+        warparty_data.last_claim_time = clock.unix_timestamp;
+
+        Ok(())
+    }
+
+    pub fn set_royalty(
+        ctx : Context<SetRoyalty>,
+        _royalty : u64,
+        _start_time : u64
+    ) -> ProgramResult {
+        msg!("+ set royalty");
+
+        let tier_clan = &mut ctx.accounts.tier_clan;
+        let tier_company = &mut ctx.accounts.tier_company;
+        let tier_warparty = &mut ctx.accounts.tier_warparty;
+
+        tier_clan.royalty = _royalty;
+        tier_clan.claim_start = _start_time;
+
+        tier_company.royalty = _royalty;
+        tier_company.claim_start = _start_time;
+
+        tier_warparty.royalty = _royalty;
+        tier_warparty.claim_start = _start_time;
+
+        Ok(())
+    }
+
+    pub fn claim_solana(
+        ctx : Context<ClaimSolana>,
+        amount : u64
+    ) -> ProgramResult {
+        msg!("+ claim solana");
+
+        let pool = &mut ctx.accounts.pool;
+
+        let signer_seeds = &[
+            pool.rand.as_ref(),
+            &[pool.bump],
+        ];
+
+        let instruction = transfer(
+            &pool.key(),
+            &ctx.accounts.owner.key(), 
+            amount
+        );
+
+        invoke_signed(
+            &instruction, 
+            &[
+                pool.to_account_info().clone(),
+                ctx.accounts.owner.to_account_info().clone(),
+                ctx.accounts.system_program.to_account_info().clone()
+            ], 
+            &[signer_seeds]
+        );
+
+        Ok(())
+    }
+}
+
+fn verify_nft_mint(nft_mint : &Mint) -> ProgramResult {
+    if nft_mint.decimals != 0 || nft_mint.supply != 1 {
+        msg!("This mint is not proper nft");
+        return Err(PoolError::InvalidTokenMint.into());
+    }
+    Ok(())
+}
+
+/// Checks that `edition` is the Token Metadata edition PDA of `mint` and holds
+/// a master or print edition, which only exists for genuine NFTs.
+fn verify_nft_edition(edition_info : &AccountInfo, mint : &Pubkey) -> ProgramResult {
+    let program_id = metaplex_token_metadata::id();
+    let (edition_key, _) = Pubkey::find_program_address(
+        &[PREFIX.as_bytes(), program_id.as_ref(), mint.as_ref(), EDITION.as_bytes()],
+        &program_id,
+    );
+    if edition_info.key() != edition_key || *edition_info.owner != program_id {
+        msg!("Not the edition of this mint");
+        return Err(PoolError::InvalidEdition.into());
+    }
+    let data = edition_info.try_borrow_data()?;
+    match data.first() {
+        Some(key) if *key == Key::MasterEditionV1 as u8
+            || *key == Key::MasterEditionV2 as u8
+            || *key == Key::EditionV1 as u8 => Ok(()),
+        _ => {
+            msg!("Not an edition account");
+            Err(PoolError::InvalidEdition.into())
+        }
+    }
+}
+
+/// Checks that `metadata` is the Token Metadata PDA of `mint` and reads it.
+/// Claims and unstakes check only this, so a policy or metadata change never
+/// traps a stake.
+fn verify_metadata_account(metadata_info : &AccountInfo, mint : &Pubkey) -> Result<Metadata, ProgramError> {
+    let program_id = metaplex_token_metadata::id();
+    let (metadata_key, _) = Pubkey::find_program_address(
+        &[PREFIX.as_bytes(), program_id.as_ref(), mint.as_ref()],
+        &program_id,
+    );
+    if metadata_info.key() != metadata_key || *metadata_info.owner != program_id {
+        msg!("Not the metadata of this mint");
+        return Err(PoolError::InvalidMetadata.into());
+    }
+    let metadata = Metadata::from_account_info(metadata_info)?;
+    if metadata.mint != *mint {
+        msg!("Not match mint address");
+        return Err(PoolError::InvalidMetadata.into());
+    }
+    Ok(metadata)
+}
+
+/// `verify_metadata_account` plus a check that the NFT is part of
+/// `collection_data`, for new stakes.
+fn verify_nft_metadata(metadata_info : &AccountInfo, mint : &Pubkey, collection_data : &Collection) -> Result<Metadata, ProgramError> {
+    let metadata = verify_metadata_account(metadata_info, mint)?;
+    let member = match collection_data.policy {
+        CollectionPolicy::FirstCreator => metadata.data.creators
+            .as_ref()
+            .and_then(|creators| creators.first())
+            .map_or(false, |creator| creator.address == collection_data.creator),
+        CollectionPolicy::VerifiedCreator => metadata.data.creators
+            .as_ref()
+            .map_or(false, |creators| creators.iter().any(|creator| creator.verified && creator.address == collection_data.creator)),
+        CollectionPolicy::VerifiedCollection => metadata_collection(&metadata, metadata_info)?
+            .map_or(false, |collection| collection.verified && collection.key == collection_data.creator),
+    };
+    if !member {
+        msg!("Not match collection");
+        return Err(PoolError::InvalidMetadata.into());
+    }
+    Ok(metadata)
+}
+
+/// `collection` as newer Token Metadata versions store it, after the fields
+/// `Metadata` knows about.
+#[derive(AnchorDeserialize)]
+struct MetadataCollection {
+    verified : bool,
+    key : Pubkey,
+}
+
+/// Reads the Metaplex collection of an NFT, skipping the `token_standard`
+/// that precedes it. Metadata written before collections existed has none.
+fn metadata_collection(metadata : &Metadata, metadata_info : &AccountInfo) -> Result<Option<MetadataCollection>, ProgramError> {
+    let known = metadata.try_to_vec().map_err(|_| PoolError::InvalidMetadata)?.len();
+    let data = metadata_info.try_borrow_data()?;
+    let mut tail = data.get(known..).unwrap_or(&[]);
+    if tail.is_empty() {
+        return Ok(None);
+    }
+    let _token_standard = Option::<u8>::deserialize(&mut tail).map_err(|_| PoolError::InvalidMetadata)?;
+    let collection = Option::<MetadataCollection>::deserialize(&mut tail).map_err(|_| PoolError::InvalidMetadata)?;
+    Ok(collection)
+}
+
+/// Sets up a fresh `StakeData` for `mint`, not yet staked.
+fn init_stake_account(stake_data : &mut StakeData, bump : u8, owner : Pubkey, mint : Pubkey, pool : Pubkey) {
+    stake_data.bump = bump;
+    stake_data.locked = false;
+    stake_data.lock_period = 0;
+    stake_data.owner = owner;
+    stake_data.mint = mint;
+    stake_data.pool = pool;
+    stake_data.unstaked = true;
+    stake_data.last_claim_time = 0;
+    stake_data.stake_time = 0;
+    stake_data.lock_start = 0;
+    stake_data.weight = 0;
+    stake_data.reward_debt = 0;
+    stake_data.multiplier_bps = rewards::BPS as u32;
+    stake_data.owed = 0;
+    stake_data.soft = false;
+    stake_data.receipt_mint = Pubkey::default();
+    stake_data.unbond_time = 0;
+    stake_data.release_time = 0;
+    stake_data.paused_time = 0;
+    stake_data.early_penalty = 0;
+}
+
+/// Checks that `authority` controls the position: its owner, or for a
+/// position with a receipt, the holder of the receipt token account passed as
+/// the first of `remaining_accounts`.
+fn verify_position_authority(stake_data : &StakeData, authority : &Pubkey, remaining_accounts : &[AccountInfo]) -> ProgramResult {
+    if stake_data.receipt_mint == Pubkey::default() {
+        if stake_data.owner != *authority {
+            return Err(PoolError::InvalidOwner.into());
+        }
+        return Ok(());
+    }
+    let receipt_info = remaining_accounts.first().ok_or(PoolError::ReceiptRequired)?;
+    let receipt_account : Account<TokenAccount> = Account::try_from(receipt_info)?;
+    if receipt_account.owner != *authority
+        || receipt_account.mint != stake_data.receipt_mint
+        || receipt_account.amount != 1 {
+        return Err(PoolError::ReceiptRequired.into());
+    }
+    Ok(())
+}
+
+/// Whether `stake_data` is still inside its lock. An unbonding stake is judged
+/// at its unstake request, as that is when it stopped earning.
+fn lock_active(pool : &Pool, stake_data : &StakeData, now : i64) -> Result<bool, ProgramError> {
+    let at = if stake_data.release_time != 0 { stake_data.unbond_time } else { now };
+    let lock_end = rewards::lock_end(&StakeSnapshot::from(stake_data), &PoolSnapshot::from(pool))?;
+    Ok(lock_end.map_or(false, |end| at <= end))
+}
+
+/// With an unbonding period set, a stake can only be unstaked once
+/// `request_unstake` has run and its cooldown has passed.
+fn verify_released(pool : &Pool, stake_data : &StakeData, now : i64) -> ProgramResult {
+    if stake_data.release_time == 0 {
+        if pool.unbonding_period > 0 {
+            msg!("Request the unstake first");
+            return Err(PoolError::UnbondingRequired.into());
+        }
+        return Ok(());
+    }
+    if now < stake_data.release_time {
+        msg!("Stake is still unbonding");
+        return Err(PoolError::InvalidTime.into());
+    }
+    Ok(())
+}
+
+/// Rejects positions that have a receipt; those are only managed by whoever
+/// holds it, through `receipt_claim` and `receipt_unstake`.
+fn verify_owner_held(stake_data : &StakeData) -> ProgramResult {
+    if stake_data.receipt_mint != Pubkey::default() {
+        msg!("Position is held through its receipt");
+        return Err(PoolError::ReceiptRequired.into());
+    }
+    Ok(())
+}
+
+/// Burns the holder's receipt once its position is fully settled, which
+/// hands the `StakeData` back to its owner.
+fn retire_receipt<'info>(
+    stake_data : &mut StakeData,
+    receipt_mint : AccountInfo<'info>,
+    receipt_account : AccountInfo<'info>,
+    holder : AccountInfo<'info>,
+    token_program : AccountInfo<'info>,
+) -> ProgramResult {
+    if !stake_data.unstaked || stake_data.owed > 0 {
+        return Ok(());
+    }
+
+    let cpi_accounts = Burn {
+        mint: receipt_mint,
+        to: receipt_account,
+        authority: holder,
+    };
+
+    let token_cpi_ctx = CpiContext::new(token_program, cpi_accounts);
+
+    token::burn(token_cpi_ctx, 1)?;
+
+    stake_data.receipt_mint = Pubkey::default();
+    Ok(())
+}
+
+/// Everything `stake` and `soft_stake` check before taking an NFT: mint,
+/// edition and metadata, the staking window, the lock option and the rarity proof.
+/// Returns the verified rarity multiplier.
+fn check_stake(
+    pool : &Pool,
+    collection_data : &ProgramAccount<Collection>,
+    metadata : &AccountInfo,
+    edition : &AccountInfo,
+    nft_mint : &Account<Mint>,
+    locked : bool,
+    lock_period : u64,
+    rarity_multiplier_bps : u32,
+    rarity_proof : &[[u8; 32]],
+    now : i64,
+) -> Result<u32, ProgramError> {
+    pool.status.verify_staking()?;
+    verify_nft_mint(nft_mint)?;
+    verify_nft_edition(edition, &nft_mint.key())?;
+    let nft_metadata = verify_nft_metadata(metadata, &nft_mint.key(), collection_data)?;
+    collection_data.eligibility.check(&nft_metadata)?;
+    let (window_start, window_end) = rewards::accrual_window(
+        &CollectionSnapshot::from(&**collection_data),
+        &PoolSnapshot::from(pool),
+    );
+    if now < window_start {
+        msg!("Staking has not started");
+        return Err(PoolError::PoolNotStarted.into());
+    }
+    if window_end.map_or(false, |end| now >= end) {
+        msg!("Staking has ended");
+        return Err(PoolError::PoolEnded.into());
+    }
+    if locked && collection_data.find_lock_option(lock_period).is_none() {
+        msg!("Lock period is not offered by this collection");
+        return Err(PoolError::InvalidLockPeriod.into());
+    }
+    if collection_data.rarity_root == [0u8; 32] {
+        return Ok(rewards::BPS as u32);
+    }
+    let leaf = merkle::rarity_leaf(&nft_mint.key(), rarity_multiplier_bps);
+    if !merkle::verify(rarity_proof, &collection_data.rarity_root, leaf) {
+        msg!("Rarity proof does not match collection");
+        return Err(PoolError::InvalidProof.into());
+    }
+    Ok(rarity_multiplier_bps)
+}
+
+/// Records a new stake on `stake_data` once its NFT is held or frozen.
+fn open_stake(
+    pool : &mut ProgramAccount<Pool>,
+    collection_data : &mut ProgramAccount<Collection>,
+    wallet : &mut WalletStats,
+    stake_data : &mut StakeData,
+    locked : bool,
+    lock_period : u64,
+    multiplier_bps : u32,
+    soft : bool,
+    now : i64,
+) -> ProgramResult {
+    if !stake_data.unstaked {
+        msg!("Stake is already active");
+        return Err(PoolError::StakeDataInUse.into());
+    }
+    if stake_data.receipt_mint != Pubkey::default() {
+        msg!("A receipt for the previous stake is still outstanding");
+        return Err(PoolError::StakeDataInUse.into());
+    }
+    if pool.max_per_wallet > 0 && wallet.staked_count >= pool.max_per_wallet {
+        msg!("Wallet stake limit reached");
+        return Err(PoolError::StakeLimitReached.into());
+    }
+    if collection_data.max_staked > 0 && collection_data.stats.staked_count >= collection_data.max_staked {
+        msg!("Collection stake limit reached");
+        return Err(PoolError::StakeLimitReached.into());
+    }
+    if locked {
+        collection_data.check_lock_capacity(lock_period)?;
+    }
+    stake_data.unstaked = false;
+    stake_data.unbond_time = 0;
+    stake_data.release_time = 0;
+    stake_data.paused_time = 0;
+    stake_data.early_penalty = 0;
+    stake_data.stake_time = now;
+    stake_data.last_claim_time = now;
+    stake_data.locked = locked;
+    stake_data.lock_period = if locked { lock_period } else { 0 };
+    stake_data.lock_start = now;
+    stake_data.multiplier_bps = multiplier_bps;
+    stake_data.collection = collection_data.key();
+    stake_data.soft = soft;
+
+    if pool.mode == PoolMode::Shared {
+        pool.update_emission(now)?;
+        let weight = rewards::stake_weight(
+            &StakeSnapshot::from(&*stake_data),
+            &CollectionSnapshot::from(&**collection_data),
+            &PoolSnapshot::from(&**pool),
+            now,
+        )?;
+        pool.set_stake_weight(stake_data, weight)?;
+    }
+
+    pool.stats.add_stake()?;
+    collection_data.stats.add_stake()?;
+    wallet.staked_count = wallet.staked_count.checked_add(1).ok_or(PoolError::MathOverflow)?;
+    if locked {
+        pool.stats.add_lock()?;
+        collection_data.add_lock(lock_period)?;
+    }
+
+    Ok(())
+}
+
+/// Token Metadata instructions that freeze and thaw a token account through
+/// its delegate, using the edition's freeze authority.
+const FREEZE_DELEGATED_ACCOUNT : u8 = 26;
+const THAW_DELEGATED_ACCOUNT : u8 = 27;
+
+/// Accounts Token Metadata needs to freeze or thaw a soft-staked NFT.
+struct SoftStakeAccounts<'info> {
+    user_nft_account : AccountInfo<'info>,
+    edition : AccountInfo<'info>,
+    nft_mint : AccountInfo<'info>,
+    token_program : AccountInfo<'info>,
+    token_metadata_program : AccountInfo<'info>,
+}
+
+/// Freezes or thaws (`instruction` is one of `FREEZE_DELEGATED_ACCOUNT` and
+/// `THAW_DELEGATED_ACCOUNT`) a token account the pool is delegate of.
+fn delegated_freeze<'info>(
+    pool : &ProgramAccount<'info, Pool>,
+    instruction : u8,
+    accounts : SoftStakeAccounts<'info>,
+) -> ProgramResult {
+    let ix = Instruction {
+        program_id : metaplex_token_metadata::id(),
+        accounts : vec![
+            AccountMeta::new_readonly(pool.key(), true),
+            AccountMeta::new(accounts.user_nft_account.key(), false),
+            AccountMeta::new_readonly(accounts.edition.key(), false),
+            AccountMeta::new_readonly(accounts.nft_mint.key(), false),
+            AccountMeta::new_readonly(accounts.token_program.key(), false),
+        ],
+        data : vec![instruction],
+    };
+
+    let signer_seeds = &[
+        pool.rand.as_ref(),
+        &[pool.bump],
+    ];
+
+    invoke_signed(
+        &ix,
+        &[
+            pool.to_account_info(),
+            accounts.user_nft_account,
+            accounts.edition,
+            accounts.nft_mint,
+            accounts.token_program,
+            accounts.token_metadata_program,
+        ],
+        &[&signer_seeds[..]],
+    )
+}
+
+/// Splits off the vesting accounts `pay_reward` reads from the front of a
+/// batch's `remaining_accounts`.
+fn split_vesting_accounts<'c, 'info>(
+    pool : &Pool,
+    remaining_accounts : &'c [AccountInfo<'info>],
+) -> (&'c [AccountInfo<'info>], &'c [AccountInfo<'info>]) {
+    let count = if pool.vesting_duration > 0 { remaining_accounts.len().min(2) } else { 0 };
+    remaining_accounts.split_at(count)
+}
+
+/// Thaws a soft-staked NFT and revokes the pool's delegation over it.
+fn release_soft_stake<'info>(
+    pool : &ProgramAccount<'info, Pool>,
+    accounts : SoftStakeAccounts<'info>,
+    owner : AccountInfo<'info>,
+) -> ProgramResult {
+    let user_nft_account = accounts.user_nft_account.clone();
+    let token_program = accounts.token_program.clone();
+
+    delegated_freeze(pool, THAW_DELEGATED_ACCOUNT, accounts)?;
+
+    invoke(
+        &spl_token::instruction::revoke(
+            &spl_token::id(),
+            user_nft_account.key,
+            owner.key,
+            &[],
+        )?,
+        &[
+            user_nft_account,
+            owner,
+            token_program,
+        ],
+    )
+}
+
+/// Token accounts a reward payout moves funds between.
+struct RewardAccounts<'info> {
+    pool_reward_account : AccountInfo<'info>,
+    user_reward_account : AccountInfo<'info>,
+    token_program : AccountInfo<'info>,
+}
+
+/// Pays `amount` of the pool's reward token to `owner`. When the pool vests
+/// rewards, the tokens go to the vesting vault instead and are credited to the
+/// owner's `VestingAccount`; both are expected as the first two
+/// `remaining_accounts`.
+fn pay_reward<'info>(
+    pool : &ProgramAccount<'info, Pool>,
+    owner : &Pubkey,
+    accounts : RewardAccounts<'info>,
+    remaining_accounts : &[AccountInfo<'info>],
+    amount : u64,
+    now : i64,
+    program_id : &Pubkey,
+) -> ProgramResult {
+    if pool.vesting_duration == 0 {
+        return transfer_from_pool(
+            pool,
+            accounts.pool_reward_account,
+            accounts.user_reward_account,
+            accounts.token_program,
+            amount,
+        );
+    }
+
+    if remaining_accounts.len() < 2 {
+        msg!("Vesting account and vault are required");
+        return Err(PoolError::InvalidVestingAccount.into());
+    }
+    let mut vesting : Account<VestingAccount> = Account::try_from(&remaining_accounts[0])?;
+    if vesting.owner != *owner || vesting.pool != pool.key() || remaining_accounts[1].key() != pool.vesting_vault {
+        return Err(PoolError::InvalidVestingAccount.into());
+    }
+
+    vesting.deposit(amount, now, pool.vesting_cliff, pool.vesting_duration)?;
+    vesting.exit(program_id)?;
+
+    transfer_from_pool(
+        pool,
+        accounts.pool_reward_account,
+        remaining_accounts[1].clone(),
+        accounts.token_program,
+        amount,
+    )
+}
+
+/// Mints the position's receipt token to `receipt_account` and hands the
+/// position over to whoever holds it.
+fn mint_receipt<'info>(
+    pool : &ProgramAccount<'info, Pool>,
+    stake_data : &mut StakeData,
+    receipt_mint : AccountInfo<'info>,
+    receipt_account : AccountInfo<'info>,
+    token_program : AccountInfo<'info>,
+) -> ProgramResult {
+    let receipt_key = receipt_mint.key();
+    let cpi_accounts = MintTo {
+        mint: receipt_mint,
+        to: receipt_account,
+        authority: pool.to_account_info().clone(),
+    };
+
+    let signer_seeds = &[
+        pool.rand.as_ref(),
+        &[pool.bump],
+    ];
+
+    let signer = &[&signer_seeds[..]];
+
+    let token_cpi_ctx = CpiContext::new_with_signer(token_program, cpi_accounts, signer);
+
+    token::mint_to(token_cpi_ctx, 1)?;
+
+    stake_data.receipt_mint = receipt_key;
+    Ok(())
+}
+
+fn transfer_from_pool<'info>(
+    pool : &ProgramAccount<'info, Pool>,
+    from : AccountInfo<'info>,
+    to : AccountInfo<'info>,
+    token_program : AccountInfo<'info>,
+    amount : u64,
+) -> ProgramResult {
+    let cpi_accounts = Transfer {
+        from,
+        to,
+        authority: pool.to_account_info().clone(),
+    };
+
+    let signer_seeds = &[
+        pool.rand.as_ref(),
+        &[pool.bump],
+    ];
+
+    let signer = &[&signer_seeds[..]];
+
+    let token_cpi_ctx = CpiContext::new_with_signer(token_program, cpi_accounts, signer);
+
+    token::transfer(token_cpi_ctx, amount)
+}
+
+#[derive(Accounts)]
+#[instruction(_bump : u8)]
+pub struct InitPool<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(init, 
+        seeds=[(*rand.key).as_ref()], 
+        bump=_bump, 
+        payer=owner, 
+        space=8+POOL_SIZE)]
+    pool : ProgramAccount<'info, Pool>,
+
+    rand : AccountInfo<'info>,
+
+    #[account(owner=spl_token::id())]
+    reward_mint : Account<'info, Mint>,
+
+    system_program : Program<'info,System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePool<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    new_owner : AccountInfo<'info>,
+
+    #[account(mut,
+        has_one = owner,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(owner=spl_token::id())]
+    reward_mint : Account<'info, Mint>,
+
+    clock : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePoolConfig<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        has_one = owner,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    clock : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(_bump : u8)]
+pub struct InitCollection<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        has_one = owner,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    creator : AccountInfo<'info>,
+
+    #[account(init,
+        seeds=[pool.key().as_ref(), (*creator.key).as_ref()],
+        bump=_bump,
+        payer=owner,
+        space=8+COLLECTION_SIZE)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    system_program : Program<'info,System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateCollection<'info> {
+    #[account(mut, signer)]
+    owner : AccountInfo<'info>,
+
+    #[account(mut,
+        has_one = owner,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    clock : AccountInfo<'info>,
+
+    system_program : Program<'info,System>,
+}
+
+#[derive(Accounts)]
+#[instruction(_bump : u8)]
+pub struct InitStakeData<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,owner=spl_token::id())]
+    nft_mint : Account<'info, Mint>,
+
+    #[account(init, 
+        seeds=[nft_mint.key().as_ref(), owner.key().as_ref(), pool.key().as_ref()], 
+        bump=_bump, 
+        payer=owner, 
+        space=8+STAKE_DATA_SIZE)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    system_program : Program<'info,System>,
+}
+
+#[derive(Accounts)]
+#[instruction(_bump : u8)]
+pub struct InitWallet<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(init,
+        seeds=[pool.key().as_ref(), owner.key().as_ref(), b"wallet".as_ref()],
+        bump=_bump,
+        payer=owner,
+        space=8+WALLET_STATS_SIZE)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    system_program : Program<'info,System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseStakeData<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump,
+        close = owner)]
+    stake_data : ProgramAccount<'info,StakeData>,
+}
+
+#[derive(Accounts)]
+#[instruction(_bump : u8)]
+pub struct InitAndStake<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(owner=spl_token::id())]
+    nft_mint : Account<'info, Mint>,
+
+    #[account(init, 
+        seeds=[nft_mint.key().as_ref(), owner.key().as_ref(), pool.key().as_ref()], 
+        bump=_bump, 
+        payer=owner, 
+        space=8+STAKE_DATA_SIZE)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    metadata : AccountInfo<'info>,
+
+    edition : AccountInfo<'info>,
+
+    #[account(mut,
+        constraint = user_nft_account.owner == owner.key(),
+        constraint = user_nft_account.mint == nft_mint.key())]
+    user_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_nft_account.owner == pool.key(),
+        constraint = pool_nft_account.mint == nft_mint.key())]
+    pool_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    token_program:Program<'info, Token>,
+
+    system_program : Program<'info,System>,
+
+    clock : AccountInfo<'info>,    
+}
+
+#[derive(Accounts)]
+pub struct Stake<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(mut,
+        owner=spl_token::id(),
+        constraint = nft_mint.key() == stake_data.mint)]
+    nft_mint : Account<'info, Mint>,
+
+    #[account(mut)]
+    metadata : AccountInfo<'info>,
+
+    edition : AccountInfo<'info>,
+
+    #[account(mut,
+        constraint = user_nft_account.owner == owner.key(),
+        constraint = user_nft_account.mint == nft_mint.key())]
+    user_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_nft_account.owner == pool.key(),
+        constraint = pool_nft_account.mint == nft_mint.key())]
+    pool_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,    
+}
+
+#[derive(Accounts)]
+pub struct Unstake<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        constraint = stake_data.collection == collection_data.key(),
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(mut,
+        owner=spl_token::id(),
+        constraint = nft_mint.key() == stake_data.mint)]
+    nft_mint : Account<'info, Mint>,
+
+    #[account(mut)]
+    metadata : AccountInfo<'info>,
+
+    #[account(mut,
+        constraint = user_nft_account.owner == owner.key(),
+        constraint = user_nft_account.mint == nft_mint.key())]
+    user_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_nft_account.owner == pool.key(),
+        constraint = pool_nft_account.mint == nft_mint.key())]
+    pool_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = user_reward_account.owner == owner.key(),
+        constraint = user_reward_account.mint == pool.reward_mint)]
+    user_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_reward_account.owner == pool.key(),
+        constraint = pool_reward_account.mint == pool.reward_mint)]
+    pool_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,    
+}
+
+#[derive(Accounts)]
+pub struct SoftStake<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(owner=spl_token::id(),
+        constraint = nft_mint.key() == stake_data.mint)]
+    nft_mint : Account<'info, Mint>,
+
+    metadata : AccountInfo<'info>,
+
+    edition : AccountInfo<'info>,
+
+    #[account(mut,
+        constraint = user_nft_account.owner == owner.key(),
+        constraint = user_nft_account.mint == nft_mint.key(),
+        constraint = user_nft_account.amount == 1)]
+    user_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    token_program:Program<'info, Token>,
+
+    #[account(constraint = token_metadata_program.key() == metaplex_token_metadata::id())]
+    token_metadata_program : AccountInfo<'info>,
+
+    clock : AccountInfo<'info>,    
+}
+
+#[derive(Accounts)]
+pub struct SoftUnstake<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        constraint = stake_data.collection == collection_data.key(),
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(owner=spl_token::id(),
+        constraint = nft_mint.key() == stake_data.mint)]
+    nft_mint : Account<'info, Mint>,
+
+    metadata : AccountInfo<'info>,
+
+    edition : AccountInfo<'info>,
+
+    #[account(mut,
+        constraint = user_nft_account.owner == owner.key(),
+        constraint = user_nft_account.mint == nft_mint.key())]
+    user_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = user_reward_account.owner == owner.key(),
+        constraint = user_reward_account.mint == pool.reward_mint)]
+    user_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_reward_account.owner == pool.key(),
+        constraint = pool_reward_account.mint == pool.reward_mint)]
+    pool_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    token_program:Program<'info, Token>,
+
+    #[account(constraint = token_metadata_program.key() == metaplex_token_metadata::id())]
+    token_metadata_program : AccountInfo<'info>,
+
+    clock : AccountInfo<'info>,    
+}
+
+#[derive(Accounts)]
+pub struct EmergencyUnstake<'info> {
+    #[account(mut)]
+    authority : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut,
+        has_one = pool,
+        constraint = stake_data.collection == collection_data.key(),
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(mut,
+        has_one = pool,
+        constraint = wallet.owner == stake_data.owner,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    #[account(constraint = nft_mint.key() == stake_data.mint)]
+    nft_mint : Account<'info, Mint>,
+
+    #[account(mut,
+        constraint = user_nft_account.owner == authority.key(),
+        constraint = user_nft_account.mint == nft_mint.key())]
+    user_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_nft_account.owner == pool.key(),
+        constraint = pool_nft_account.mint == nft_mint.key())]
+    pool_nft_account : Account<'info, TokenAccount>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,    
+}
+
+#[derive(Accounts)]
+pub struct SetEarlyUnlock<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        has_one = owner,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(
+        constraint = treasury_account.mint == pool.reward_mint)]
+    treasury_account : Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct EarlyUnstake<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        constraint = stake_data.collection == collection_data.key(),
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(mut,
+        owner=spl_token::id(),
+        constraint = nft_mint.key() == stake_data.mint)]
+    nft_mint : Account<'info, Mint>,
+
+    #[account(mut)]
+    metadata : AccountInfo<'info>,
+
+    #[account(mut,
+        constraint = user_nft_account.owner == owner.key(),
+        constraint = user_nft_account.mint == nft_mint.key())]
+    user_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_nft_account.owner == pool.key(),
+        constraint = pool_nft_account.mint == nft_mint.key())]
+    pool_nft_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = user_reward_account.owner == owner.key(),
+        constraint = user_reward_account.mint == pool.reward_mint)]
+    user_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_reward_account.owner == pool.key(),
+        constraint = pool_reward_account.mint == pool.reward_mint)]
+    pool_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = treasury_account.key() == pool.treasury)]
+    treasury_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,    
+}
+
+#[derive(Accounts)]
+pub struct Claim<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut)]
+    metadata : AccountInfo<'info>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        constraint = stake_data.collection == collection_data.key(),
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(mut,
+        constraint = user_reward_account.owner == owner.key(),
+        constraint = user_reward_account.mint == pool.reward_mint)]
+    user_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_reward_account.owner == pool.key(),
+        constraint = pool_reward_account.mint == pool.reward_mint)]
+    pool_reward_account : Account<'info, TokenAccount>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,  
+}
+
+#[derive(Accounts)]
+pub struct StakeBatch<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,  
+}
+
+#[derive(Accounts)]
+pub struct ClaimBatch<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut,
+        constraint = user_reward_account.owner == owner.key(),
+        constraint = user_reward_account.mint == pool.reward_mint)]
+    user_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_reward_account.owner == pool.key(),
+        constraint = pool_reward_account.mint == pool.reward_mint)]
+    pool_reward_account : Account<'info, TokenAccount>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,  
+}
+
+#[derive(Accounts)]
+pub struct UnstakeBatch<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut,
+        constraint = user_reward_account.owner == owner.key(),
+        constraint = user_reward_account.mint == pool.reward_mint)]
+    user_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_reward_account.owner == pool.key(),
+        constraint = pool_reward_account.mint == pool.reward_mint)]
+    pool_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,  
+}
+
+#[derive(Accounts)]
+pub struct Relock<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut)]
+    metadata : AccountInfo<'info>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        constraint = stake_data.collection == collection_data.key(),
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(mut,
+        constraint = user_reward_account.owner == owner.key(),
+        constraint = user_reward_account.mint == pool.reward_mint)]
+    user_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_reward_account.owner == pool.key(),
+        constraint = pool_reward_account.mint == pool.reward_mint)]
+    pool_reward_account : Account<'info, TokenAccount>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,  
+}
+
+#[derive(Accounts)]
+pub struct IssueReceipt<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
+    #[account(
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = owner,
+        has_one = pool,
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(init,
+        payer = owner,
+        mint::decimals = 0,
+        mint::authority = pool)]
+    receipt_mint : Account<'info, Mint>,
+
+    #[account(init,
+        payer = owner,
+        token::mint = receipt_mint,
+        token::authority = owner)]
+    receipt_account : Account<'info, TokenAccount>,
+
+    token_program:Program<'info, Token>,
+
+    system_program : Program<'info,System>,
+
+    rent : Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct ReceiptClaim<'info> {
+    #[account(mut)]
+    holder : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    metadata : AccountInfo<'info>,
+
+    #[account(mut,
+        has_one = pool,
+        has_one = receipt_mint,
+        constraint = stake_data.collection == collection_data.key(),
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(mut)]
+    receipt_mint : Account<'info, Mint>,
+
+    #[account(mut,
+        constraint = receipt_account.owner == holder.key(),
+        constraint = receipt_account.mint == receipt_mint.key(),
+        constraint = receipt_account.amount == 1)]
+    receipt_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = user_reward_account.owner == holder.key(),
+        constraint = user_reward_account.mint == pool.reward_mint)]
+    user_reward_account : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = pool_reward_account.owner == pool.key(),
+        constraint = pool_reward_account.mint == pool.reward_mint)]
+    pool_reward_account : Account<'info, TokenAccount>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,  
+}
+
+#[derive(Accounts)]
+pub struct ReceiptUnstake<'info> {
+    #[account(mut)]
+    holder : Signer<'info>,
+
+    #[account(mut,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(mut,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
+
+    #[account(mut,
+        has_one = pool,
+        has_one = receipt_mint,
+        constraint = stake_data.collection == collection_data.key(),
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(mut)]
+    receipt_mint : Account<'info, Mint>,
+
+    #[account(mut,
+        constraint = receipt_account.owner == holder.key(),
+        constraint = receipt_account.mint == receipt_mint.key(),
+        constraint = receipt_account.amount == 1)]
+    receipt_account : Account<'info, TokenAccount>,
+
+    #[account(owner=spl_token::id(),
+        constraint = nft_mint.key() == stake_data.mint)]
+    nft_mint : Account<'info, Mint>,
+
+    metadata : AccountInfo<'info>,
 
-        Ok(())
-    }
+    #[account(mut,
+        constraint = user_nft_account.owner == holder.key(),
+        constraint = user_nft_account.mint == nft_mint.key())]
+    user_nft_account : Account<'info, TokenAccount>,
 
-    pub fn claim_solana(
-        ctx : Context<ClaimSolana>,
-        amount : u64
-    ) -> ProgramResult {
-        msg!("+ claim solana");
+    #[account(mut,
+        constraint = pool_nft_account.owner == pool.key(),
+        constraint = pool_nft_account.mint == nft_mint.key())]
+    pool_nft_account : Account<'info, TokenAccount>,
 
-        let pool = &mut ctx.accounts.pool;
+    #[account(mut,
+        constraint = user_reward_account.owner == holder.key(),
+        constraint = user_reward_account.mint == pool.reward_mint)]
+    user_reward_account : Account<'info, TokenAccount>,
 
-        let signer_seeds = &[
-            pool.rand.as_ref(),
-            &[pool.bump],
-        ];
+    #[account(mut,
+        constraint = pool_reward_account.owner == pool.key(),
+        constraint = pool_reward_account.mint == pool.reward_mint)]
+    pool_reward_account : Account<'info, TokenAccount>,
 
-        let instruction = transfer(
-            &pool.key(),
-            &ctx.accounts.owner.key(), 
-            amount
-        );
+    #[account(mut,
+        has_one = pool,
+        constraint = wallet.owner == stake_data.owner,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
 
-        invoke_signed(
-            &instruction, 
-            &[
-                pool.to_account_info().clone(),
-                ctx.accounts.owner.to_account_info().clone(),
-                ctx.accounts.system_program.to_account_info().clone()
-            ], 
-            &[signer_seeds]
-        );
+    token_program:Program<'info, Token>,
 
-        Ok(())
-    }
+    clock : AccountInfo<'info>,    
 }
 
 #[derive(Accounts)]
 #[instruction(_bump : u8)]
-pub struct InitPool<'info> {
+pub struct InitRewardStream<'info> {
     #[account(mut)]
     owner : Signer<'info>,
 
-    #[account(init, 
-        seeds=[(*rand.key).as_ref()], 
-        bump=_bump, 
-        payer=owner, 
-        space=8+POOL_SIZE)]
+    #[account(
+        has_one = owner,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
     pool : ProgramAccount<'info, Pool>,
 
-    rand : AccountInfo<'info>,
-
     #[account(owner=spl_token::id())]
     reward_mint : Account<'info, Mint>,
 
+    #[account(
+        constraint = stream_vault.owner == pool.key(),
+        constraint = stream_vault.mint == reward_mint.key())]
+    stream_vault : Account<'info, TokenAccount>,
+
+    #[account(init,
+        seeds=[pool.key().as_ref(), reward_mint.key().as_ref()],
+        bump=_bump,
+        payer=owner,
+        space=8+REWARD_STREAM_SIZE)]
+    stream : ProgramAccount<'info, RewardStream>,
+
+    clock : AccountInfo<'info>,
+
     system_program : Program<'info,System>,
 }
 
 #[derive(Accounts)]
-pub struct UpdatePool<'info> {
+pub struct UpdateRewardStream<'info> {
     #[account(mut)]
     owner : Signer<'info>,
 
-    new_owner : AccountInfo<'info>,
-
-    #[account(mut,
+    #[account(
         has_one = owner,
         seeds = [pool.rand.as_ref()], 
         bump = pool.bump)]
     pool : ProgramAccount<'info, Pool>,
 
-    #[account(owner=spl_token::id())]
-    reward_mint : Account<'info, Mint>,
+    #[account(mut,
+        has_one = pool,
+        seeds=[stream.pool.as_ref(), stream.reward_mint.as_ref()],
+        bump=stream.bump)]
+    stream : ProgramAccount<'info, RewardStream>,
 }
 
 #[derive(Accounts)]
 #[instruction(_bump : u8)]
-pub struct InitCollection<'info> {
+pub struct InitStakeStream<'info> {
     #[account(mut)]
     owner : Signer<'info>,
 
-    #[account(mut,
+    #[account(
         has_one = owner,
-        seeds = [pool.rand.as_ref()], 
-        bump = pool.bump)]
-    pool : ProgramAccount<'info, Pool>,
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
 
-    creator : AccountInfo<'info>,
+    #[account(
+        constraint = stream.pool == stake_data.pool,
+        seeds=[stream.pool.as_ref(), stream.reward_mint.as_ref()],
+        bump=stream.bump)]
+    stream : ProgramAccount<'info, RewardStream>,
 
     #[account(init,
-        seeds=[pool.key().as_ref(), (*creator.key).as_ref()],
+        seeds=[stake_data.key().as_ref(), stream.key().as_ref()],
         bump=_bump,
         payer=owner,
-        space=8+COLLECTION_SIZE)]
-    collection_data : ProgramAccount<'info,Collection>,
+        space=8+STAKE_STREAM_SIZE)]
+    stake_stream : ProgramAccount<'info, StakeStream>,
 
     system_program : Program<'info,System>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateCollection<'info> {
-    #[account(mut, signer)]
-    owner : AccountInfo<'info>,
+pub struct ClaimStream<'info> {
+    #[account(mut)]
+    authority : Signer<'info>,
+
+    #[account(
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
+
+    #[account(
+        has_one = pool,
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(
+        has_one = pool,
+        seeds=[stream.pool.as_ref(), stream.reward_mint.as_ref()],
+        bump=stream.bump)]
+    stream : ProgramAccount<'info, RewardStream>,
 
     #[account(mut,
-        has_one = owner,
+        constraint = stake_stream.stake_data == stake_data.key(),
+        constraint = stake_stream.stream == stream.key(),
+        seeds=[stake_stream.stake_data.as_ref(), stake_stream.stream.as_ref()],
+        bump=stake_stream.bump)]
+    stake_stream : ProgramAccount<'info, StakeStream>,
+
+    #[account(mut,
+        constraint = stream_vault.key() == stream.vault)]
+    stream_vault : Account<'info, TokenAccount>,
+
+    #[account(mut,
+        constraint = user_stream_account.owner == authority.key(),
+        constraint = user_stream_account.mint == stream.reward_mint)]
+    user_stream_account : Account<'info, TokenAccount>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimStreams<'info> {
+    #[account(mut)]
+    authority : Signer<'info>,
+
+    #[account(
         seeds = [pool.rand.as_ref()], 
         bump = pool.bump)]
     pool : ProgramAccount<'info, Pool>,
 
+    #[account(
+        has_one = pool,
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    token_program:Program<'info, Token>,
+
+    clock : AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetVesting<'info> {
+    #[account(mut)]
+    owner : Signer<'info>,
+
     #[account(mut,
         has_one = owner,
-        has_one = pool,
-        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
-        bump=collection_data.bump)]
-    collection_data : ProgramAccount<'info,Collection>,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
 
-    system_program : Program<'info,System>,
+    #[account(
+        constraint = vesting_vault.owner == pool.key(),
+        constraint = vesting_vault.mint == pool.reward_mint)]
+    vesting_vault : Account<'info, TokenAccount>,
 }
 
 #[derive(Accounts)]
 #[instruction(_bump : u8)]
-pub struct InitStakeData<'info> {
+pub struct InitVesting<'info> {
     #[account(mut)]
     owner : Signer<'info>,
 
@@ -976,142 +3911,143 @@ pub struct InitStakeData<'info> {
         bump = pool.bump)]
     pool : ProgramAccount<'info, Pool>,
 
-    #[account(mut,owner=spl_token::id())]
-    nft_mint : Account<'info, Mint>,
-
-    #[account(init, 
-        seeds=[nft_mint.key().as_ref(), owner.key().as_ref(), pool.key().as_ref()], 
-        bump=_bump, 
-        payer=owner, 
-        space=8+STAKE_DATA_SIZE)]
-    stake_data : ProgramAccount<'info,StakeData>,
+    #[account(init,
+        seeds=[pool.key().as_ref(), owner.key().as_ref(), b"vesting".as_ref()],
+        bump=_bump,
+        payer=owner,
+        space=8+VESTING_SIZE)]
+    vesting : ProgramAccount<'info, VestingAccount>,
 
     system_program : Program<'info,System>,
 }
 
 #[derive(Accounts)]
-pub struct Stake<'info> {
+pub struct ReleaseVested<'info> {
     #[account(mut)]
     owner : Signer<'info>,
 
-    #[account(mut,
+    #[account(
         seeds = [pool.rand.as_ref()], 
         bump = pool.bump)]
     pool : ProgramAccount<'info, Pool>,
 
-    #[account(
-        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
-        bump=collection_data.bump)]
-    collection_data : ProgramAccount<'info,Collection>,
-
     #[account(mut,
         has_one = owner,
         has_one = pool,
-        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
-        bump=stake_data.bump)]
-    stake_data : ProgramAccount<'info,StakeData>,
-
-    #[account(mut,owner=spl_token::id())]
-    nft_mint : Account<'info, Mint>,
-
-    #[account(mut)]
-    metadata : AccountInfo<'info>,
+        seeds=[vesting.pool.as_ref(), vesting.owner.as_ref(), b"vesting".as_ref()],
+        bump=vesting.bump)]
+    vesting : ProgramAccount<'info, VestingAccount>,
 
     #[account(mut,
-        constraint = user_nft_account.owner == owner.key(),
-        constraint = user_nft_account.mint == nft_mint.key())]
-    user_nft_account : Account<'info, TokenAccount>,
+        constraint = vesting_vault.key() == pool.vesting_vault)]
+    vesting_vault : Account<'info, TokenAccount>,
 
     #[account(mut,
-        constraint = pool_nft_account.owner == pool.key(),
-        constraint = pool_nft_account.mint == nft_mint.key())]
-    pool_nft_account : Account<'info, TokenAccount>,
+        constraint = user_reward_account.owner == owner.key(),
+        constraint = user_reward_account.mint == pool.reward_mint)]
+    user_reward_account : Account<'info, TokenAccount>,
 
     token_program:Program<'info, Token>,
 
-    clock : AccountInfo<'info>,    
+    clock : AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct Unstake<'info> {
-    #[account(mut)]
-    owner : Signer<'info>,
+pub struct ChangeUnbonding<'info> {
+    authority : Signer<'info>,
 
     #[account(mut,
         seeds = [pool.rand.as_ref()], 
         bump = pool.bump)]
     pool : ProgramAccount<'info, Pool>,
 
-    #[account(
+    #[account(mut,
+        has_one = pool,
         seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
         bump=collection_data.bump)]
     collection_data : ProgramAccount<'info,Collection>,
 
     #[account(mut,
-        has_one = owner,
         has_one = pool,
+        constraint = stake_data.collection == collection_data.key(),
         seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
         bump=stake_data.bump)]
     stake_data : ProgramAccount<'info,StakeData>,
 
-    #[account(mut,owner=spl_token::id())]
-    nft_mint : Account<'info, Mint>,
-
-    #[account(mut)]
-    metadata : AccountInfo<'info>,
-
-    #[account(mut,
-        constraint = user_nft_account.owner == owner.key(),
-        constraint = user_nft_account.mint == nft_mint.key())]
-    user_nft_account : Account<'info, TokenAccount>,
+    clock : AccountInfo<'info>,
+}
 
+#[derive(Accounts)]
+pub struct RefreshWeight<'info> {
     #[account(mut,
-        constraint = pool_nft_account.owner == pool.key(),
-        constraint = pool_nft_account.mint == nft_mint.key())]
-    pool_nft_account : Account<'info, TokenAccount>,
+        seeds = [pool.rand.as_ref()], 
+        bump = pool.bump)]
+    pool : ProgramAccount<'info, Pool>,
 
     #[account(mut,
-        constraint = user_reward_account.owner == owner.key(),
-        constraint = user_reward_account.mint == pool.reward_mint)]
-    user_reward_account : Account<'info, TokenAccount>,
+        has_one = pool,
+        seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
+        bump=collection_data.bump)]
+    collection_data : ProgramAccount<'info,Collection>,
 
     #[account(mut,
-        constraint = pool_reward_account.owner == pool.key(),
-        constraint = pool_reward_account.mint == pool.reward_mint)]
-    pool_reward_account : Account<'info, TokenAccount>,
-
-    token_program:Program<'info, Token>,
+        has_one = pool,
+        constraint = stake_data.collection == collection_data.key(),
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
 
-    clock : AccountInfo<'info>,    
+    clock : AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct Claim<'info> {
+pub struct ForceUnstake<'info> {
     #[account(mut)]
     owner : Signer<'info>,
 
-    #[account(
+    #[account(mut,
+        has_one = owner,
         seeds = [pool.rand.as_ref()], 
         bump = pool.bump)]
     pool : ProgramAccount<'info, Pool>,
 
-    #[account(
+    #[account(mut,
+        has_one = pool,
         seeds=[collection_data.pool.as_ref(), collection_data.creator.as_ref()],
         bump=collection_data.bump)]
     collection_data : ProgramAccount<'info,Collection>,
 
-    #[account(mut)]
-    metadata : AccountInfo<'info>,
+    #[account(mut,
+        has_one = pool,
+        constraint = stake_data.collection == collection_data.key(),
+        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
+        bump=stake_data.bump)]
+    stake_data : ProgramAccount<'info,StakeData>,
+
+    #[account(mut,
+        has_one = pool,
+        constraint = wallet.owner == stake_data.owner,
+        seeds=[wallet.pool.as_ref(), wallet.owner.as_ref(), b"wallet".as_ref()],
+        bump=wallet.bump)]
+    wallet : ProgramAccount<'info, WalletStats>,
+
+    #[account(constraint = nft_mint.key() == stake_data.mint)]
+    nft_mint : Account<'info, Mint>,
+
+    edition : AccountInfo<'info>,
+
+    #[account(mut,
+        constraint = user_nft_account.owner == stake_data.owner,
+        constraint = user_nft_account.mint == nft_mint.key())]
+    user_nft_account : Account<'info, TokenAccount>,
 
     #[account(mut,
-        has_one = owner,
-        has_one = pool,
-        seeds=[stake_data.mint.as_ref(), stake_data.owner.as_ref(), stake_data.pool.as_ref()], 
-        bump=stake_data.bump)]
-    stake_data : ProgramAccount<'info,StakeData>,
+        constraint = pool_nft_account.owner == pool.key(),
+        constraint = pool_nft_account.mint == nft_mint.key())]
+    pool_nft_account : Account<'info, TokenAccount>,
 
     #[account(mut,
-        constraint = user_reward_account.owner == owner.key(),
+        constraint = user_reward_account.owner == stake_data.owner,
         constraint = user_reward_account.mint == pool.reward_mint)]
     user_reward_account : Account<'info, TokenAccount>,
 
@@ -1122,31 +4058,38 @@ pub struct Claim<'info> {
 
     token_program:Program<'info, Token>,
 
-    clock : AccountInfo<'info>,  
+    #[account(constraint = token_metadata_program.key() == metaplex_token_metadata::id())]
+    token_metadata_program : AccountInfo<'info>,
+
+    clock : AccountInfo<'info>,    
 }
 
 #[derive(Accounts)]
-pub struct Withdraw<'info> {
+pub struct RescueNft<'info> {
     #[account(mut)]
     owner : Signer<'info>,
 
     #[account(
+        has_one = owner,
         seeds = [pool.rand.as_ref()],
         bump = pool.bump)]
     pool : ProgramAccount<'info, Pool>,
 
-    #[account(mut, owner = spl_token::id())]
+    #[account(owner = spl_token::id())]
     nft_mint : Account<'info, Mint>,
 
     #[account(mut,
-        constraint = user_nft_account.owner == owner.key(),
-        constraint = user_nft_account.mint == nft_mint.key())]
-    user_nft_account : Account<'info, TokenAccount>,
+        constraint = pool_nft_account.owner == pool.key(),
+        constraint = pool_nft_account.mint == nft_mint.key())]
+    pool_nft_account : Account<'info, TokenAccount>,
 
     #[account(mut,
-        constraint = pool_nft_account.owner == owner.key(),
-        constraint = pool_nft_account.mint == pool.key())]
-    pool_nft_account : Account<'info, TokenAccount>,
+        constraint = destination_account.mint == nft_mint.key())]
+    destination_account : Account<'info, TokenAccount>,
+
+    stream : AccountInfo<'info>,
+
+    stake_data : AccountInfo<'info>,
 
     token_program: Program<'info, Token>
 }
@@ -1488,14 +4431,40 @@ pub struct ClaimSolana<'info> {
     system_program : Program<'info,System>
 }
 
-pub const POOL_SIZE : usize = 32 + 32 + 32 + 8 + 8 + 1 + 1;
-pub const COLLECTION_SIZE : usize = 32 + 1 + 32 + 8 + 8 + 8 + 8 + 32 + 4 + 32 * 20;
+pub const POOL_SIZE : usize = 32 + 32 + 32 + 8 + 8 + STAKE_STATS_SIZE + 1 + 1 + 8 + 16 + 8 + 8 + SCHEDULE_SIZE + 8 + 8 + 8 + 32 + 8 + 1 + 8 + 8 + 32 + 8 + 8 + 1;
+pub const SCHEDULE_SIZE : usize = 1 + 4 + SCHEDULE_STEP_SIZE * MAX_SCHEDULE_STEPS;
+pub const SCHEDULE_STEP_SIZE : usize = 8 + 8;
+pub const MAX_SCHEDULE_STEPS : usize = 16;
+pub const MAX_SCHEDULE_RATE_BPS : u64 = 10 * rewards::BPS;
+pub const MAX_HALVING_INTERVAL : u64 = 1_000_000;
+pub const COLLECTION_SIZE : usize = 32 + 1 + 32 + 8 + 4 + LOCK_OPTION_SIZE * MAX_LOCK_OPTIONS + 32 + 32 + 8 + 8 + 4 + RATE_CHECKPOINT_SIZE * MAX_RATE_CHECKPOINTS + 1 + STAKE_STATS_SIZE + 4 + LOCK_STAT_SIZE * MAX_LOCK_STATS + 8 + ELIGIBILITY_SIZE;
+pub const ELIGIBILITY_SIZE : usize = 2 * (4 + (4 + MAX_SYMBOL_LENGTH) * MAX_ELIGIBILITY_SYMBOLS) + 1 + 4 + MAX_NAME_LENGTH + 2;
+pub const MAX_ELIGIBILITY_SYMBOLS : usize = 4;
+pub const STAKE_STATS_SIZE : usize = 8 + 8 + 8;
+pub const LOCK_STAT_SIZE : usize = 8 + 8;
+pub const MAX_LOCK_STATS : usize = 16;
+pub const RATE_CHECKPOINT_SIZE : usize = 8 + 8 + 4 + LOCK_OPTION_SIZE * MAX_LOCK_OPTIONS;
+pub const MAX_RATE_CHECKPOINTS : usize = 8;
+pub const LOCK_OPTION_SIZE : usize = 8 + 8 + 1 + 2 + 8;
+pub const MAX_LOCK_OPTIONS : usize = 8;
 pub const TIER_DATA_SIZE : usize = 32 + 1 + 32 + 4 + 1 + 8 + 8 + 8 + 8 + 4 + MAX_SYMBOL_LENGTH;
-pub const STAKE_DATA_SIZE : usize = 1 + 1 + 8 + 32 + 32 + 32 + 8 + 8 + 1;
+pub const STAKE_DATA_SIZE : usize = 1 + 1 + 8 + 32 + 32 + 32 + 8 + 8 + 1 + 8 + 16 + 4 + 32 + 8 + 8 + 8 + 1 + 32 + 8 + 8 + 8 + 8;
+pub const REWARD_STREAM_SIZE : usize = 32 + 32 + 32 + 8 + 4 + STREAM_RATE_SIZE * MAX_STREAM_RATES + 1;
+pub const STREAM_RATE_SIZE : usize = 32 + 8;
+pub const MAX_STREAM_RATES : usize = 10;
+pub const STAKE_STREAM_SIZE : usize = 32 + 32 + 8 + 8 + 1 + 8;
+pub const WALLET_STATS_SIZE : usize = 32 + 32 + 8 + 1;
+pub const VESTING_SIZE : usize = 32 + 32 + 8 + 4 + VESTING_TRANCHE_SIZE * MAX_VESTING_TRANCHES + 8 + 8 + 1;
+pub const VESTING_TRANCHE_SIZE : usize = 8 + 8 + 8 + 8 + 8;
+pub const MAX_VESTING_TRANCHES : usize = 32;
+pub const VESTING_TRANCHE_WINDOW : i64 = 86_400;
 pub const CLAN_DATA_SIZE : usize = 32 + 1 + 32 + 32 + 1 + 8 + 8 + 4 + 32 * 7;
 pub const COMPANY_DATA_SIZE : usize = 32 + 1 + 32 + 32  + 1 + 8 + 8 + 4 + 32 * 15;
 pub const WARPARTY_DATA_SIZE : usize = 32 + 1 + 32 + 32  + 1 + 8 + 8 + 4 + 32 * 35;
 pub const PERIOD : i64 = 24 * 60 * 60;
+pub const STAKE_BATCH_GROUP : usize = 6;
+pub const UNSTAKE_BATCH_GROUP : usize = 5;
+pub const CLAIM_BATCH_GROUP : usize = 2;
 
 #[account]
 pub struct Pool {
@@ -1504,8 +4473,313 @@ pub struct Pool {
     pub reward_mint : Pubkey,
     pub start_time : i64,
     pub period : i64,
-    pub tvl : u8,
+    pub stats : StakeStats,
     pub bump : u8,
+    pub mode : PoolMode,
+    pub emission_rate : u64,
+    pub reward_per_weight : u128,
+    pub total_weight : u64,
+    pub last_update_time : i64,
+    pub schedule : EmissionSchedule,
+    pub end_time : i64,
+    /// Claimed rewards vest linearly over `vesting_duration` seconds after a
+    /// `vesting_cliff`; zero duration pays rewards out directly.
+    pub vesting_cliff : i64,
+    pub vesting_duration : i64,
+    pub vesting_vault : Pubkey,
+    pub total_owed : u64,
+    pub early_unlock_enabled : bool,
+    pub early_unlock_penalty_bps : u64,
+    pub early_unlock_fee : u64,
+    /// Token account receiving early unlock penalties; may be the reward vault.
+    pub treasury : Pubkey,
+    /// Seconds between `request_unstake` and the NFT becoming unstakeable;
+    /// zero lets stakes leave at once.
+    pub unbonding_period : i64,
+    /// Most NFTs one wallet may have staked at once; zero means no cap.
+    pub max_per_wallet : u64,
+    pub status : PoolStatus,
+}
+
+impl Pool {
+    /// Brings `reward_per_weight` up to `now`. A no-op for `PoolMode::Fixed`
+    /// pools since their `total_weight` stays zero.
+    pub fn update_emission(&mut self, now : i64) -> ProgramResult {
+        self.reward_per_weight = rewards::accumulate(&EmissionSnapshot::from(&*self), now)?;
+        self.last_update_time = self.last_update_time.max(now);
+        Ok(())
+    }
+
+    /// Adds `reward` to what the stake is already owed and works out how much
+    /// of that the vault's `available` balance covers without touching what
+    /// other stakes are owed. The remainder stays on the books in
+    /// `StakeData.owed` and `Pool.total_owed` and is paid first on the
+    /// stake's next claim.
+    pub fn record_payout(&mut self, collection_data : &mut Collection, stake_data : &mut StakeData, reward : u64, available : u64) -> Result<u64, ProgramError> {
+        let due = stake_data.owed.checked_add(reward).ok_or(PoolError::MathOverflow)?;
+        let others_owed = self.total_owed.checked_sub(stake_data.owed).ok_or(PoolError::MathOverflow)?;
+        let paid = rewards::payout(stake_data.owed, reward, others_owed, available);
+        let owed = due - paid;
+
+        self.total_owed = self.total_owed
+            .checked_sub(stake_data.owed)
+            .and_then(|total| total.checked_add(owed))
+            .ok_or(PoolError::MathOverflow)?;
+        stake_data.owed = owed;
+
+        if owed > 0 {
+            msg!("Reward vault short, {} recorded as owed", owed);
+        }
+        self.stats.add_paid(paid)?;
+        collection_data.stats.add_paid(paid)?;
+        Ok(paid)
+    }
+
+    /// Rewards the stake has earned since its last claim. Shared-mode stakes
+    /// are also checkpointed against the accumulator and get their weight
+    /// refreshed.
+    pub fn settle_accrued(&mut self, collection_data : &Collection, stake_data : &mut StakeData, now : i64) -> Result<u64, ProgramError> {
+        // An unbonding stake was settled by `request_unstake` and earns nothing.
+        if stake_data.release_time != 0 {
+            return Ok(0);
+        }
+        match self.mode {
+            PoolMode::Fixed => rewards::calculate_reward(
+                &StakeSnapshot::from(&*stake_data),
+                &CollectionSnapshot::from(collection_data),
+                &PoolSnapshot::from(&*self),
+                now,
+            )?.total(),
+            PoolMode::Shared => {
+                self.update_emission(now)?;
+                let pending = rewards::pending_shared(stake_data.weight, self.reward_per_weight, stake_data.reward_debt)?;
+                let weight = rewards::stake_weight(
+                    &StakeSnapshot::from(&*stake_data),
+                    &CollectionSnapshot::from(collection_data),
+                    &PoolSnapshot::from(&*self),
+                    now,
+                )?;
+                self.set_stake_weight(stake_data, weight)?;
+                Ok(pending)
+            }
+        }
+    }
+
+    /// Marks a stake as unstaked once its NFT has been returned.
+    pub fn close_stake(&mut self, collection_data : &mut Collection, wallet : &mut WalletStats, stake_data : &mut StakeData, now : i64) -> ProgramResult {
+        self.stats.remove_stake()?;
+        collection_data.stats.remove_stake()?;
+        wallet.staked_count = wallet.staked_count.checked_sub(1).ok_or(PoolError::MathOverflow)?;
+        if stake_data.locked {
+            self.stats.remove_lock()?;
+            collection_data.remove_lock(stake_data.lock_period)?;
+        }
+        stake_data.unstaked = true;
+        stake_data.unstake_time = now;
+        stake_data.locked = false;
+        stake_data.lock_period = 0;
+        Ok(())
+    }
+
+    /// Replaces a stake's share weight and resets its debt to the current
+    /// accumulator. Pending rewards must be read before calling this.
+    pub fn set_stake_weight(&mut self, stake_data : &mut StakeData, weight : u64) -> ProgramResult {
+        self.total_weight = self.total_weight
+            .checked_sub(stake_data.weight)
+            .and_then(|total| total.checked_add(weight))
+            .ok_or(PoolError::MathOverflow)?;
+        stake_data.weight = weight;
+        stake_data.reward_debt = rewards::reward_debt(weight, self.reward_per_weight)?;
+        Ok(())
+    }
+}
+
+/// How `verify_nft_metadata` decides an NFT belongs to a collection, always
+/// by comparing against `Collection.creator`: the first listed creator, any
+/// verified creator, or the verified Metaplex collection key.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum CollectionPolicy {
+    FirstCreator,
+    VerifiedCreator,
+    VerifiedCollection,
+}
+
+/// Metadata rules an NFT must meet on top of `CollectionPolicy` to be staked.
+/// They are not rechecked on claim or unstake, so tightening them never traps
+/// existing stakes. Empty `allowed_symbols` admits any symbol not in
+/// `denied_symbols`, an empty `name_prefix` any name.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct Eligibility {
+    pub allowed_symbols : Vec<String>,
+    pub denied_symbols : Vec<String>,
+    pub require_primary_sale : bool,
+    pub name_prefix : String,
+    pub min_seller_fee_bps : u16,
+}
+
+impl Eligibility {
+    /// The rules every collection had before they were configurable.
+    pub fn legacy() -> Self {
+        Eligibility {
+            allowed_symbols : Vec::new(),
+            denied_symbols : vec![String::from("IV")],
+            require_primary_sale : true,
+            name_prefix : String::new(),
+            min_seller_fee_bps : 0,
+        }
+    }
+
+    pub fn validate(&self) -> ProgramResult {
+        if self.allowed_symbols.len() > MAX_ELIGIBILITY_SYMBOLS || self.denied_symbols.len() > MAX_ELIGIBILITY_SYMBOLS {
+            msg!("Too many symbols");
+            return Err(PoolError::InvalidEligibility.into());
+        }
+        if self.allowed_symbols.iter().chain(self.denied_symbols.iter()).any(|symbol| symbol.len() > MAX_SYMBOL_LENGTH) {
+            msg!("Symbol too long");
+            return Err(PoolError::InvalidEligibility.into());
+        }
+        if self.name_prefix.len() > MAX_NAME_LENGTH {
+            msg!("Name prefix too long");
+            return Err(PoolError::InvalidEligibility.into());
+        }
+        if u64::from(self.min_seller_fee_bps) > rewards::BPS {
+            return Err(PoolError::InvalidEligibility.into());
+        }
+        Ok(())
+    }
+
+    /// Token Metadata pads `name` and `symbol` with NULs, which are ignored.
+    pub fn check(&self, metadata : &Metadata) -> ProgramResult {
+        let symbol = metadata.data.symbol.trim_end_matches(char::from(0));
+        let name = metadata.data.name.trim_end_matches(char::from(0));
+
+        if self.denied_symbols.iter().any(|denied| denied == symbol) {
+            msg!("Symbol is denied");
+            return Err(PoolError::InvalidMetadata.into());
+        }
+        if !self.allowed_symbols.is_empty() && !self.allowed_symbols.iter().any(|allowed| allowed == symbol) {
+            msg!("Symbol is not allowed");
+            return Err(PoolError::InvalidMetadata.into());
+        }
+        if self.require_primary_sale && !metadata.primary_sale_happened {
+            msg!("Primary sale has not happened");
+            return Err(PoolError::InvalidMetadata.into());
+        }
+        if !name.starts_with(self.name_prefix.as_str()) {
+            msg!("Name does not match");
+            return Err(PoolError::InvalidMetadata.into());
+        }
+        if metadata.data.seller_fee_basis_points < self.min_seller_fee_bps {
+            msg!("Seller fee too low");
+            return Err(PoolError::InvalidMetadata.into());
+        }
+        Ok(())
+    }
+}
+
+/// What a pool currently allows. `PausedStaking` stops new stakes and groups;
+/// `PausedAll` also stops claims and unstakes; `Emergency` only lets NFTs
+/// leave through `emergency_unstake` and `emergency_soft_unstake`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PoolStatus {
+    Active,
+    PausedStaking,
+    PausedAll,
+    Emergency,
+}
+
+impl PoolStatus {
+    pub fn verify_staking(&self) -> ProgramResult {
+        if *self != PoolStatus::Active {
+            msg!("Pool is not taking stakes");
+            return Err(PoolError::InvalidPoolStatus.into());
+        }
+        Ok(())
+    }
+
+    pub fn verify_rewards(&self) -> ProgramResult {
+        match self {
+            PoolStatus::Active | PoolStatus::PausedStaking => Ok(()),
+            _ => {
+                msg!("Pool is paused");
+                Err(PoolError::InvalidPoolStatus.into())
+            }
+        }
+    }
+
+    pub fn verify_emergency(&self) -> ProgramResult {
+        if *self != PoolStatus::Emergency {
+            msg!("Pool is not in emergency mode");
+            return Err(PoolError::InvalidPoolStatus.into());
+        }
+        Ok(())
+    }
+}
+
+/// How a pool pays its stakers. `Fixed` pays every stake its collection's
+/// per-period rate; `Shared` splits `emission_rate` per period across all
+/// staked weight.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PoolMode {
+    Fixed,
+    Shared,
+}
+
+/// Curve the pool's base reward rate follows, as a basis-point factor of the
+/// configured rate (`Collection.reward_normal`, lock rates, or
+/// `Pool.emission_rate`). Periods are counted from `Pool.start_time`.
+///
+/// In `PoolMode::Fixed` pools a new schedule also applies to rewards that
+/// have accrued but not yet been claimed.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum EmissionSchedule {
+    Flat,
+    /// The rate halves every `interval` periods.
+    StepHalving { interval : u64 },
+    /// The rate drops by `decay_bps` each period until it reaches `floor_bps`.
+    LinearDecay { decay_bps : u64, floor_bps : u64 },
+    /// Each step sets the rate from its `start_period` on; the full rate
+    /// applies before the first step.
+    Piecewise { steps : Vec<ScheduleStep> },
+}
+
+impl EmissionSchedule {
+    pub fn validate(&self) -> ProgramResult {
+        match self {
+            EmissionSchedule::Flat => {}
+            EmissionSchedule::StepHalving { interval } => {
+                if *interval == 0 || *interval > MAX_HALVING_INTERVAL {
+                    return Err(PoolError::InvalidSchedule.into());
+                }
+            }
+            EmissionSchedule::LinearDecay { decay_bps, floor_bps } => {
+                if *decay_bps > rewards::BPS || *floor_bps > rewards::BPS {
+                    return Err(PoolError::InvalidSchedule.into());
+                }
+            }
+            EmissionSchedule::Piecewise { steps } => {
+                if steps.len() > MAX_SCHEDULE_STEPS {
+                    msg!("Too many schedule steps");
+                    return Err(PoolError::InvalidSchedule.into());
+                }
+                if steps.windows(2).any(|pair| pair[0].start_period >= pair[1].start_period) {
+                    msg!("Schedule steps must be in increasing order");
+                    return Err(PoolError::InvalidSchedule.into());
+                }
+                if steps.iter().any(|step| step.rate_bps > MAX_SCHEDULE_RATE_BPS) {
+                    msg!("Schedule rate is too high");
+                    return Err(PoolError::InvalidSchedule.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleStep {
+    pub start_period : u64,
+    pub rate_bps : u64,
 }
 
 #[account]
@@ -1514,11 +4788,194 @@ pub struct Collection {
     pub bump : u8,
     pub pool : Pubkey,
     pub reward_normal : u64,
-    pub reward_locked_one : u64,
-    pub reward_locked_two : u64,
-    pub reward_locked_three : u64,
+    pub lock_options : Vec<LockOption>,
     pub creator : Pubkey,
-    pub ultras : Vec<Pubkey>
+    /// Root of a Merkle tree of `(mint, multiplier_bps)` leaves, see
+    /// `merkle::rarity_leaf`. All zeroes disables rarity multipliers.
+    pub rarity_root : [u8; 32],
+    pub start_time : i64,
+    pub end_time : i64,
+    /// Rates superseded by `update_collection`, oldest first.
+    pub rate_history : Vec<RateCheckpoint>,
+    /// How NFTs are matched against `creator`.
+    pub policy : CollectionPolicy,
+    pub stats : StakeStats,
+    /// Locked stakes per lock duration, including durations no longer
+    /// offered. Entries are dropped once their count reaches zero.
+    pub lock_stats : Vec<LockStat>,
+    /// Most NFTs staked in the collection at once; zero means no cap.
+    pub max_staked : u64,
+    pub eligibility : Eligibility,
+}
+
+impl Collection {
+    /// Records the current rates as having applied until `now`. History is
+    /// only needed by live stakes, so it is cleared while the collection has
+    /// none; otherwise the update is refused once `MAX_RATE_CHECKPOINTS` are
+    /// stored rather than repricing idle stakes with newer rates.
+    pub fn checkpoint_rates(&mut self, now : i64) -> ProgramResult {
+        if self.stats.staked_count == 0 {
+            self.rate_history.clear();
+        }
+        if self.rate_history.len() >= MAX_RATE_CHECKPOINTS {
+            msg!("Rate history is full");
+            return Err(PoolError::RateHistoryFull.into());
+        }
+        self.rate_history.push(RateCheckpoint {
+            until : now,
+            reward_normal : self.reward_normal,
+            lock_options : self.lock_options.clone(),
+        });
+        Ok(())
+    }
+
+    pub fn add_lock(&mut self, duration : u64) -> ProgramResult {
+        self.stats.add_lock()?;
+        match self.lock_stats.iter_mut().find(|stat| stat.duration == duration) {
+            Some(stat) => {
+                stat.locked_count = stat.locked_count.checked_add(1).ok_or(PoolError::MathOverflow)?;
+            }
+            None => {
+                if self.lock_stats.len() >= MAX_LOCK_STATS {
+                    msg!("Too many lock durations in use");
+                    return Err(PoolError::InvalidLockPeriod.into());
+                }
+                self.lock_stats.push(LockStat { duration, locked_count : 1 });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_lock(&mut self, duration : u64) -> ProgramResult {
+        self.stats.remove_lock()?;
+        let index = self.lock_stats
+            .iter()
+            .position(|stat| stat.duration == duration)
+            .ok_or(PoolError::MathOverflow)?;
+        let stat = &mut self.lock_stats[index];
+        stat.locked_count = stat.locked_count.checked_sub(1).ok_or(PoolError::MathOverflow)?;
+        if stat.locked_count == 0 {
+            self.lock_stats.remove(index);
+        }
+        Ok(())
+    }
+
+    /// Fails when the lock option for `duration` is at its `max_locked`.
+    pub fn check_lock_capacity(&self, duration : u64) -> ProgramResult {
+        let max_locked = self.find_lock_option(duration).map_or(0, |option| option.max_locked);
+        let locked = self.lock_stats
+            .iter()
+            .find(|stat| stat.duration == duration)
+            .map_or(0, |stat| stat.locked_count);
+        if max_locked > 0 && locked >= max_locked {
+            msg!("Lock option is full");
+            return Err(PoolError::StakeLimitReached.into());
+        }
+        Ok(())
+    }
+
+    pub fn find_lock_option(&self, duration : u64) -> Option<&LockOption> {
+        self.lock_options.iter().find(|option| option.duration == duration)
+    }
+
+    pub fn validate_lock_options(lock_options : &[LockOption]) -> ProgramResult {
+        if lock_options.len() > MAX_LOCK_OPTIONS {
+            msg!("Too many lock options");
+            return Err(PoolError::InvalidLockPeriod.into());
+        }
+        for (i, option) in lock_options.iter().enumerate() {
+            if option.duration == 0 {
+                return Err(PoolError::InvalidLockPeriod.into());
+            }
+            if lock_options[..i].iter().any(|other| other.duration == option.duration) {
+                msg!("Duplicate lock option");
+                return Err(PoolError::InvalidLockPeriod.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How many NFTs a wallet has staked in a pool, for `Pool.max_per_wallet`.
+#[account]
+pub struct WalletStats {
+    pub owner : Pubkey,
+    pub pool : Pubkey,
+    pub staked_count : u64,
+    pub bump : u8,
+}
+
+/// Staking totals of a pool or collection, maintained by the program alone.
+/// A locked stake counts as locked until it is unstaked or relocked, even
+/// once its lock has run out.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct StakeStats {
+    pub staked_count : u64,
+    pub locked_count : u64,
+    /// Rewards paid out of the reward vault, including into vesting.
+    pub rewards_paid : u64,
+}
+
+impl StakeStats {
+    pub fn add_stake(&mut self) -> ProgramResult {
+        self.staked_count = self.staked_count.checked_add(1).ok_or(PoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn remove_stake(&mut self) -> ProgramResult {
+        self.staked_count = self.staked_count.checked_sub(1).ok_or(PoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn add_lock(&mut self) -> ProgramResult {
+        self.locked_count = self.locked_count.checked_add(1).ok_or(PoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn remove_lock(&mut self) -> ProgramResult {
+        self.locked_count = self.locked_count.checked_sub(1).ok_or(PoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn add_paid(&mut self, amount : u64) -> ProgramResult {
+        self.rewards_paid = self.rewards_paid.checked_add(amount).ok_or(PoolError::MathOverflow)?;
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct LockStat {
+    pub duration : u64,
+    pub locked_count : u64,
+}
+
+/// Rates a collection paid until `until`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct RateCheckpoint {
+    pub until : i64,
+    pub reward_normal : u64,
+    pub lock_options : Vec<LockOption>,
+}
+
+/// Per-NFT arguments of `stake_batch`, matching those of `stake`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct StakeTerms {
+    pub locked : bool,
+    pub lock_period : u64,
+    pub rarity_multiplier_bps : u32,
+    pub rarity_proof : Vec<[u8; 32]>,
+}
+
+/// A lock length a collection offers, with the rate it earns while locked.
+/// `duration` is counted in pool periods; `multiplier_bps`, when set, scales
+/// `reward` (10000 = 1x). `max_locked` caps how many stakes may hold this
+/// lock at once; zero means no cap.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct LockOption {
+    pub duration : u64,
+    pub reward : u64,
+    pub multiplier_bps : Option<u16>,
+    pub max_locked : u64,
 }
 
 #[account]
@@ -1545,7 +5002,176 @@ pub struct StakeData {
     pub pool : Pubkey,
     pub stake_time : i64,
     pub last_claim_time : i64,
-    pub bump : u8
+    pub bump : u8,
+    pub weight : u64,
+    pub reward_debt : u128,
+    pub multiplier_bps : u32,
+    pub collection : Pubkey,
+    pub unstake_time : i64,
+    /// Rewards earned but not yet paid because the vault ran short.
+    pub owed : u64,
+    /// When the current lock started; differs from `stake_time` after `relock`.
+    pub lock_start : i64,
+    /// The NFT stays frozen in the owner's wallet instead of the pool's.
+    pub soft : bool,
+    /// Receipt token whose holder controls the position, or the default key.
+    pub receipt_mint : Pubkey,
+    /// When `request_unstake` ran and when the NFT may leave; zero when the
+    /// stake is not unbonding.
+    pub unbond_time : i64,
+    pub release_time : i64,
+    /// Seconds this stake spent unbonding before cancelled unstakes, which
+    /// reward streams do not pay for.
+    pub paused_time : i64,
+    /// Rewards forfeited by an early `request_unstake`, refunded on cancel.
+    pub early_penalty : u64,
+}
+
+/// An additional reward token paid alongside `Pool.reward_mint`, out of its
+/// own vault and at its own per-collection rates. Stream rewards follow the
+/// pool's `[start_time, end_time]` window but not its schedule or lock rates.
+#[account]
+pub struct RewardStream {
+    pub pool : Pubkey,
+    pub reward_mint : Pubkey,
+    pub vault : Pubkey,
+    pub start_time : i64,
+    pub rates : Vec<StreamRate>,
+    pub bump : u8,
+}
+
+impl RewardStream {
+    pub fn rate_for(&self, collection : &Pubkey) -> u64 {
+        self.rates
+            .iter()
+            .find(|rate| rate.collection == *collection)
+            .map_or(0, |rate| rate.reward_per_period)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct StreamRate {
+    pub collection : Pubkey,
+    pub reward_per_period : u64,
+}
+
+/// Per-stake accounting for one `RewardStream`.
+///
+/// `stake_time` mirrors `StakeData.stake_time` as of the last settlement, so a
+/// re-stake is detected and accrual restarts from the new stake. Rewards of a
+/// previous stake that were not claimed before re-staking are dropped.
+#[account]
+pub struct StakeStream {
+    pub stake_data : Pubkey,
+    pub stream : Pubkey,
+    pub stake_time : i64,
+    pub last_claim_time : i64,
+    pub bump : u8,
+    /// `StakeData.paused_time` as of `last_claim_time`.
+    pub paused_time : i64,
+}
+
+impl StakeStream {
+    /// Works out what the stake has earned from `stream` up to `now` (or up to
+    /// the unstake or unstake request) and marks it as claimed. Time spent in
+    /// cancelled cooldowns since the last claim is taken off the end.
+    pub fn settle(&mut self, pool : &Pool, stake_data : &StakeData, stream : &RewardStream, now : i64) -> Result<u64, ProgramError> {
+        let end = if stake_data.unstaked { stake_data.unstake_time } else { now };
+        let end = if stake_data.release_time != 0 { end.min(stake_data.unbond_time) } else { end };
+        let (from, paused_before) = if self.stake_time == stake_data.stake_time {
+            (self.last_claim_time, self.paused_time)
+        } else {
+            (stake_data.stake_time, 0)
+        };
+        let from = from.max(stream.start_time);
+        let paused = stake_data.paused_time.checked_sub(paused_before).ok_or(PoolError::MathOverflow)?;
+
+        let amount = rewards::calculate_stream_reward(
+            stream.rate_for(&stake_data.collection),
+            stake_data.multiplier_bps as u64,
+            &PoolSnapshot::from(pool),
+            from,
+            end.saturating_sub(paused).max(from),
+        )?;
+
+        self.stake_time = stake_data.stake_time;
+        self.last_claim_time = end.max(from);
+        self.paused_time = stake_data.paused_time;
+        Ok(amount)
+    }
+}
+
+/// Rewards a user has claimed from a vesting pool, held in `Pool.vesting_vault`.
+///
+/// Each deposit vests on its own schedule from its deposit time. Deposits on
+/// the same schedule within `VESTING_TRANCHE_WINDOW` of the newest tranche,
+/// or any deposit once `MAX_VESTING_TRANCHES` are open, join the newest
+/// tranche on its existing schedule, so funds already vesting are never
+/// pushed back. What already vested stays releasable in `vested_balance`.
+#[account]
+pub struct VestingAccount {
+    pub owner : Pubkey,
+    pub pool : Pubkey,
+    pub vested_balance : u64,
+    pub tranches : Vec<VestingTranche>,
+    pub total_deposited : u64,
+    pub total_released : u64,
+    pub bump : u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct VestingTranche {
+    pub amount : u64,
+    pub released : u64,
+    pub start_time : i64,
+    pub cliff : i64,
+    pub duration : i64,
+}
+
+impl VestingAccount {
+    pub fn deposit(&mut self, amount : u64, now : i64, cliff : i64, duration : i64) -> ProgramResult {
+        self.collect_vested(now)?;
+
+        let full = self.tranches.len() >= MAX_VESTING_TRANCHES;
+        match self.tranches.last_mut() {
+            Some(last) if full || (last.cliff == cliff && last.duration == duration && now - last.start_time < VESTING_TRANCHE_WINDOW) => {
+                last.amount = last.amount.checked_add(amount).ok_or(PoolError::MathOverflow)?;
+            }
+            _ => self.tranches.push(VestingTranche {
+                amount,
+                released : 0,
+                start_time : now,
+                cliff,
+                duration,
+            }),
+        }
+        self.total_deposited = self.total_deposited.checked_add(amount).ok_or(PoolError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Marks everything vested by `now` as released and returns the amount.
+    pub fn release(&mut self, now : i64) -> Result<u64, ProgramError> {
+        self.collect_vested(now)?;
+        let amount = self.vested_balance;
+
+        self.vested_balance = 0;
+        self.total_released = self.total_released.checked_add(amount).ok_or(PoolError::MathOverflow)?;
+        Ok(amount)
+    }
+
+    /// Moves what each tranche has vested by `now` into `vested_balance` and
+    /// drops the tranches that are fully vested.
+    fn collect_vested(&mut self, now : i64) -> ProgramResult {
+        for tranche in self.tranches.iter_mut() {
+            let vested = rewards::vested_amount(tranche.amount, tranche.start_time, tranche.cliff, tranche.duration, now)?;
+            self.vested_balance = self.vested_balance
+                .checked_add(vested - tranche.released)
+                .ok_or(PoolError::MathOverflow)?;
+            tranche.released = vested;
+        }
+        self.tranches.retain(|tranche| tranche.released < tranche.amount);
+        Ok(())
+    }
 }
 
 #[account]
@@ -1617,5 +5243,126 @@ pub enum PoolError {
     AlreadyUnstaked,
 
     #[msg("Invalid owner")]
-    InvalidOwner
-}
\ No newline at end of file
+    InvalidOwner,
+
+    #[msg("Invalid lock period")]
+    InvalidLockPeriod,
+
+    #[msg("Math overflow")]
+    MathOverflow,
+
+    #[msg("Invalid pool mode")]
+    InvalidPoolMode,
+
+    #[msg("Invalid emission schedule")]
+    InvalidSchedule,
+
+    #[msg("Pool has not started")]
+    PoolNotStarted,
+
+    #[msg("Pool has ended")]
+    PoolEnded,
+
+    #[msg("Invalid merkle proof")]
+    InvalidProof,
+
+    #[msg("Invalid reward stream")]
+    InvalidRewardStream,
+
+    #[msg("Invalid vesting account")]
+    InvalidVestingAccount,
+
+    #[msg("Early unlock is disabled")]
+    EarlyUnlockDisabled,
+
+    #[msg("Invalid penalty")]
+    InvalidPenalty,
+
+    #[msg("Invalid stake kind")]
+    InvalidStakeKind,
+
+    #[msg("Invalid batch accounts")]
+    InvalidBatch,
+
+    #[msg("Invalid edition")]
+    InvalidEdition,
+
+    #[msg("Stake data is still in use")]
+    StakeDataInUse,
+
+    #[msg("Position is held through its receipt")]
+    ReceiptRequired,
+
+    #[msg("Unstake has not been requested")]
+    UnbondingRequired,
+
+    #[msg("Stake limit reached")]
+    StakeLimitReached,
+
+    #[msg("Invalid pool status")]
+    InvalidPoolStatus,
+
+    #[msg("Invalid eligibility rules")]
+    InvalidEligibility,
+
+    #[msg("Rate history is full")]
+    RateHistoryFull
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY : i64 = 86_400;
+
+    fn vesting() -> VestingAccount {
+        VestingAccount {
+            owner : Pubkey::default(),
+            pool : Pubkey::default(),
+            vested_balance : 0,
+            tranches : Vec::new(),
+            total_deposited : 0,
+            total_released : 0,
+            bump : 0,
+        }
+    }
+
+    #[test]
+    fn deposits_in_the_window_keep_the_first_start() {
+        let mut account = vesting();
+        account.deposit(1000, 0, 0, 10 * DAY).unwrap();
+        account.deposit(1000, DAY / 2, 0, 10 * DAY).unwrap();
+        assert_eq!(account.tranches.len(), 1);
+        assert_eq!(account.tranches[0].start_time, 0);
+
+        // A claim every half day still lets the first deposit finish on time.
+        let mut now = DAY;
+        while now < 10 * DAY {
+            account.deposit(1, now, 0, 10 * DAY).unwrap();
+            now += DAY / 2;
+        }
+        assert!(account.release(10 * DAY).unwrap() >= 2000);
+    }
+
+    #[test]
+    fn schedules_out_of_bounds_are_rejected() {
+        assert!(EmissionSchedule::StepHalving { interval : 0 }.validate().is_err());
+        assert!(EmissionSchedule::StepHalving { interval : MAX_HALVING_INTERVAL }.validate().is_ok());
+        assert!(EmissionSchedule::StepHalving { interval : MAX_HALVING_INTERVAL + 1 }.validate().is_err());
+        let step = |rate_bps| EmissionSchedule::Piecewise { steps : vec![ScheduleStep { start_period : 1, rate_bps }] };
+        assert!(step(MAX_SCHEDULE_RATE_BPS).validate().is_ok());
+        assert!(step(MAX_SCHEDULE_RATE_BPS + 1).validate().is_err());
+    }
+
+    #[test]
+    fn a_full_account_never_restarts_old_tranches() {
+        let mut account = vesting();
+        for i in 0..MAX_VESTING_TRANCHES as i64 {
+            account.deposit(1000, i * DAY, 0, 100 * DAY).unwrap();
+        }
+        let newest = account.tranches[MAX_VESTING_TRANCHES - 1].start_time;
+        account.deposit(1000, 40 * DAY, 0, 100 * DAY).unwrap();
+        assert_eq!(account.tranches.len(), MAX_VESTING_TRANCHES);
+        assert_eq!(account.tranches[MAX_VESTING_TRANCHES - 1].start_time, newest);
+        assert_eq!(account.total_deposited, 1000 * (MAX_VESTING_TRANCHES as u64 + 1));
+    }
+}
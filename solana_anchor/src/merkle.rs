@@ -0,0 +1,84 @@
+//! Merkle proofs for per-mint rarity multipliers.
+//!
+//! Leaves are `keccak(mint || multiplier_bps as u32 little-endian)`. Pairs are
+//! hashed in sorted order, so proofs carry no left/right flags.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::keccak;
+
+pub fn rarity_leaf(mint : &Pubkey, multiplier_bps : u32) -> [u8; 32] {
+    keccak::hashv(&[mint.as_ref(), &multiplier_bps.to_le_bytes()]).0
+}
+
+pub fn verify(proof : &[[u8; 32]], root : &[u8; 32], leaf : [u8; 32]) -> bool {
+    let mut computed = leaf;
+    for node in proof {
+        computed = if computed <= *node {
+            keccak::hashv(&[&computed, node]).0
+        } else {
+            keccak::hashv(&[node, &computed]).0
+        };
+    }
+    computed == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_pair(a : [u8; 32], b : [u8; 32]) -> [u8; 32] {
+        if a <= b {
+            keccak::hashv(&[&a, &b]).0
+        } else {
+            keccak::hashv(&[&b, &a]).0
+        }
+    }
+
+    fn mints() -> Vec<Pubkey> {
+        (1..=4u8).map(|seed| Pubkey::new(&[seed; 32])).collect()
+    }
+
+    /// Root of a four leaf tree and the proof of each leaf.
+    fn tree(leaves : &[[u8; 32]]) -> ([u8; 32], Vec<Vec<[u8; 32]>>) {
+        let left = hash_pair(leaves[0], leaves[1]);
+        let right = hash_pair(leaves[2], leaves[3]);
+        let proofs = vec![
+            vec![leaves[1], right],
+            vec![leaves[0], right],
+            vec![leaves[3], left],
+            vec![leaves[2], left],
+        ];
+        (hash_pair(left, right), proofs)
+    }
+
+    #[test]
+    fn every_leaf_proves_against_the_root() {
+        let leaves : Vec<[u8; 32]> = mints().iter().map(|mint| rarity_leaf(mint, 15000)).collect();
+        let (root, proofs) = tree(&leaves);
+        for (leaf, proof) in leaves.iter().zip(proofs.iter()) {
+            assert!(verify(proof, &root, *leaf));
+        }
+    }
+
+    #[test]
+    fn wrong_multiplier_or_proof_is_rejected() {
+        let mints = mints();
+        let leaves : Vec<[u8; 32]> = mints.iter().map(|mint| rarity_leaf(mint, 15000)).collect();
+        let (root, proofs) = tree(&leaves);
+
+        assert!(!verify(&proofs[0], &root, rarity_leaf(&mints[0], 20000)));
+        assert!(!verify(&proofs[1], &root, leaves[0]));
+        assert!(!verify(&proofs[0][..1], &root, leaves[0]));
+
+        let mut tampered = proofs[0].clone();
+        tampered[1][0] ^= 1;
+        assert!(!verify(&tampered, &root, leaves[0]));
+    }
+
+    #[test]
+    fn single_leaf_tree_needs_no_proof() {
+        let leaf = rarity_leaf(&mints()[0], 10000);
+        assert!(verify(&[], &leaf, leaf));
+        assert!(!verify(&[], &leaf, rarity_leaf(&mints()[0], 10001)));
+    }
+}
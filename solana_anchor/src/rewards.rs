@@ -0,0 +1,849 @@
+//! Reward engine shared by `claim` and `unstake`.
+//!
+//! Everything in here works on plain snapshots of the on-chain accounts so the
+//! math can be reasoned about (and exercised) without any `AccountInfo` or CPI.
+
+use anchor_lang::prelude::*;
+use std::convert::TryFrom;
+use crate::{Collection, EmissionSchedule, LockOption, Pool, PoolError, RateCheckpoint, StakeData};
+
+/// Denominator for basis-point multipliers.
+pub const BPS : u64 = 10000;
+
+/// Fixed-point scale of `Pool.reward_per_weight`.
+pub const ACC_PRECISION : u128 = 1_000_000_000_000;
+
+pub struct StakeSnapshot {
+    pub mint : Pubkey,
+    pub multiplier_bps : u64,
+    pub locked : bool,
+    pub lock_period : u64,
+    pub lock_start : i64,
+    pub last_claim_time : i64,
+    /// When `request_unstake` ran, zero unless the stake is unbonding.
+    pub unbond_time : i64,
+}
+
+pub struct CollectionSnapshot<'a> {
+    pub start_time : i64,
+    pub end_time : i64,
+    pub reward_normal : u64,
+    pub lock_options : &'a [LockOption],
+    pub rate_history : &'a [RateCheckpoint],
+}
+
+pub struct PoolSnapshot<'a> {
+    pub start_time : i64,
+    pub end_time : i64,
+    pub period : i64,
+    pub schedule : &'a EmissionSchedule,
+}
+
+/// Global accumulator state of a `PoolMode::Shared` pool.
+pub struct EmissionSnapshot<'a> {
+    pub start_time : i64,
+    pub end_time : i64,
+    pub period : i64,
+    pub schedule : &'a EmissionSchedule,
+    pub emission_rate : u64,
+    pub reward_per_weight : u128,
+    pub total_weight : u64,
+    pub last_update_time : i64,
+}
+
+/// Itemised result of a reward calculation.
+///
+/// `locked_reward` covers the part of the claim window that falls inside the
+/// lock, `normal_reward` the part after it (or the whole window for unlocked
+/// stakes). Both already include the stake's rarity `multiplier_bps`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RewardBreakdown {
+    pub locked_reward : u64,
+    pub normal_reward : u64,
+    pub multiplier_bps : u64,
+}
+
+impl RewardBreakdown {
+    pub fn total(&self) -> Result<u64, ProgramError> {
+        self.locked_reward.checked_add(self.normal_reward).ok_or_else(|| PoolError::MathOverflow.into())
+    }
+}
+
+/// `a * b / c` computed through a u128 intermediate, failing instead of
+/// wrapping when the result does not fit back into a u64 or `c` is zero.
+pub fn mul_div(a : u64, b : u64, c : u64) -> Result<u64, ProgramError> {
+    let result = (a as u128)
+        .checked_mul(b as u128)
+        .and_then(|product| product.checked_div(c as u128))
+        .ok_or(PoolError::MathOverflow)?;
+    if result > u64::MAX as u128 {
+        return Err(PoolError::MathOverflow.into());
+    }
+    Ok(result as u64)
+}
+
+/// How much of a stake's `owed` debt plus a new `reward` the vault's
+/// `available` balance pays out. The debt is paid first; the new reward only
+/// from what is left once `others_owed`, the debt of every other stake, stays
+/// covered.
+pub fn payout(owed : u64, reward : u64, others_owed : u64, available : u64) -> u64 {
+    let debt_paid = owed.min(available);
+    let spare = (available - debt_paid).saturating_sub(others_owed);
+    debt_paid + reward.min(spare)
+}
+
+/// `rate * weighted_seconds / (period * BPS)`, i.e. a per-period `rate` paid
+/// over a span measured by `scheduled_seconds`.
+pub fn scaled_reward(rate : u64, weighted_seconds : u128, period : i64) -> Result<u64, ProgramError> {
+    if period <= 0 {
+        return Err(PoolError::InvalidPeriod.into());
+    }
+    let result = (rate as u128)
+        .checked_mul(weighted_seconds)
+        .ok_or(PoolError::MathOverflow)?
+        / (period as u128 * BPS as u128);
+    u64::try_from(result).map_err(|_| PoolError::MathOverflow.into())
+}
+
+/// Share of a tier's royalty owed to one of its `count` groups, with `share`
+/// in basis points.
+pub fn group_share(royalty : u64, share : u32, count : u64) -> Result<u64, ProgramError> {
+    let divisor = count.checked_mul(BPS).ok_or(PoolError::MathOverflow)?;
+    mul_div(royalty, share as u64, divisor)
+}
+
+impl From<&StakeData> for StakeSnapshot {
+    fn from(stake_data : &StakeData) -> Self {
+        StakeSnapshot {
+            mint : stake_data.mint,
+            multiplier_bps : stake_data.multiplier_bps as u64,
+            locked : stake_data.locked,
+            lock_period : stake_data.lock_period,
+            lock_start : stake_data.lock_start,
+            last_claim_time : stake_data.last_claim_time,
+            unbond_time : stake_data.unbond_time,
+        }
+    }
+}
+
+impl<'a> From<&'a Collection> for CollectionSnapshot<'a> {
+    fn from(collection : &'a Collection) -> Self {
+        CollectionSnapshot {
+            start_time : collection.start_time,
+            end_time : collection.end_time,
+            reward_normal : collection.reward_normal,
+            lock_options : &collection.lock_options,
+            rate_history : &collection.rate_history,
+        }
+    }
+}
+
+impl<'a> From<&'a Pool> for PoolSnapshot<'a> {
+    fn from(pool : &'a Pool) -> Self {
+        PoolSnapshot {
+            start_time : pool.start_time,
+            end_time : pool.end_time,
+            period : pool.period,
+            schedule : &pool.schedule,
+        }
+    }
+}
+
+impl<'a> From<&'a Pool> for EmissionSnapshot<'a> {
+    fn from(pool : &'a Pool) -> Self {
+        EmissionSnapshot {
+            start_time : pool.start_time,
+            end_time : pool.end_time,
+            period : pool.period,
+            schedule : &pool.schedule,
+            emission_rate : pool.emission_rate,
+            reward_per_weight : pool.reward_per_weight,
+            total_weight : pool.total_weight,
+            last_update_time : pool.last_update_time,
+        }
+    }
+}
+
+/// Per-period rate paid while a stake is inside its lock.
+///
+/// A stake whose lock option has since been removed from the collection keeps
+/// its lock but earns `reward_normal`.
+pub fn lock_rate(reward_normal : u64, lock_options : &[LockOption], lock_period : u64) -> Result<u64, ProgramError> {
+    match lock_options.iter().find(|option| option.duration == lock_period) {
+        Some(option) => match option.multiplier_bps {
+            Some(multiplier_bps) => mul_div(option.reward, multiplier_bps as u64, BPS),
+            None => Ok(option.reward),
+        },
+        None => Ok(reward_normal),
+    }
+}
+
+/// Span during which rewards accrue: the intersection of the pool's and the
+/// collection's `[start_time, end_time]`. An `end_time` of zero means open
+/// ended, as does a collection `start_time` of zero.
+pub fn accrual_window(collection : &CollectionSnapshot, pool : &PoolSnapshot) -> (i64, Option<i64>) {
+    let start = pool.start_time.max(collection.start_time);
+    let end = match (pool.end_time, collection.end_time) {
+        (0, 0) => None,
+        (0, end) | (end, 0) => Some(end),
+        (pool_end, collection_end) => Some(pool_end.min(collection_end)),
+    };
+    (start, end)
+}
+
+/// Timestamp at which the stake's lock expires, or `None` for unlocked stakes.
+pub fn lock_end(stake : &StakeSnapshot, pool : &PoolSnapshot) -> Result<Option<i64>, ProgramError> {
+    if !stake.locked {
+        return Ok(None);
+    }
+    let lock_length = i64::try_from(stake.lock_period)
+        .ok()
+        .and_then(|lock_period| lock_period.checked_mul(pool.period))
+        .ok_or(PoolError::MathOverflow)?;
+    let end = stake.lock_start.checked_add(lock_length).ok_or(PoolError::MathOverflow)?;
+    Ok(Some(end))
+}
+
+/// Rewards accrued by `stake` between its `last_claim_time` and `now`,
+/// clamped to the accrual window. An unbonding stake stops accruing at its
+/// `unbond_time`.
+///
+/// The window is split at the lock expiry: seconds before it earn the lock
+/// rate, seconds after it earn `reward_normal`. A claim that straddles the
+/// expiry therefore gets both portions, and a window of zero length (e.g.
+/// `last_claim_time == now`) earns nothing. Both rates follow the pool's
+/// emission schedule.
+///
+/// Each second is paid at the rates in force at that time: the window is also
+/// split at every checkpoint in `rate_history`, so rate changes only apply
+/// from the moment they were made.
+pub fn calculate_reward(
+    stake : &StakeSnapshot,
+    collection : &CollectionSnapshot,
+    pool : &PoolSnapshot,
+    now : i64,
+) -> Result<RewardBreakdown, ProgramError> {
+    if pool.period <= 0 {
+        return Err(PoolError::InvalidPeriod.into());
+    }
+
+    let (window_start, window_end) = accrual_window(collection, pool);
+    let from = stake.last_claim_time.max(window_start);
+    let now = if stake.unbond_time != 0 { now.min(stake.unbond_time) } else { now };
+    let to = window_end.map_or(now, |end| now.min(end)).max(from);
+
+    let lock_end = lock_end(stake, pool)?;
+    let segments = collection.rate_history
+        .iter()
+        .map(|checkpoint| (checkpoint.until, checkpoint.reward_normal, &checkpoint.lock_options[..]))
+        .chain(std::iter::once((i64::MAX, collection.reward_normal, collection.lock_options)));
+
+    let mut breakdown = RewardBreakdown {
+        multiplier_bps : stake.multiplier_bps,
+        ..RewardBreakdown::default()
+    };
+    let mut segment_start = from;
+    for (until, reward_normal, lock_options) in segments {
+        let segment_end = until.min(to);
+        if segment_end <= segment_start {
+            continue;
+        }
+
+        let (locked_seconds, normal_seconds) = match lock_end {
+            Some(end) => {
+                let split = end.max(segment_start).min(segment_end);
+                (
+                    scheduled_seconds(pool.schedule, pool.start_time, pool.period, segment_start, split)?,
+                    scheduled_seconds(pool.schedule, pool.start_time, pool.period, split, segment_end)?,
+                )
+            }
+            None => (0, scheduled_seconds(pool.schedule, pool.start_time, pool.period, segment_start, segment_end)?),
+        };
+
+        let lock_reward = mul_div(lock_rate(reward_normal, lock_options, stake.lock_period)?, stake.multiplier_bps, BPS)?;
+        let reward = mul_div(reward_normal, stake.multiplier_bps, BPS)?;
+
+        breakdown.locked_reward = breakdown.locked_reward
+            .checked_add(scaled_reward(lock_reward, locked_seconds, pool.period)?)
+            .ok_or(PoolError::MathOverflow)?;
+        breakdown.normal_reward = breakdown.normal_reward
+            .checked_add(scaled_reward(reward, normal_seconds, pool.period)?)
+            .ok_or(PoolError::MathOverflow)?;
+        segment_start = segment_end;
+    }
+
+    Ok(breakdown)
+}
+
+/// Rewards from an additional `RewardStream` between `from` and `to`, clamped
+/// to the pool's window. Streams pay a flat `rate` per period scaled by the
+/// stake's rarity multiplier.
+pub fn calculate_stream_reward(
+    rate : u64,
+    multiplier_bps : u64,
+    pool : &PoolSnapshot,
+    from : i64,
+    to : i64,
+) -> Result<u64, ProgramError> {
+    if pool.period <= 0 {
+        return Err(PoolError::InvalidPeriod.into());
+    }
+    let from = from.max(pool.start_time);
+    let to = if pool.end_time == 0 { to } else { to.min(pool.end_time) }.max(from);
+    mul_div(mul_div(rate, multiplier_bps, BPS)?, seconds_between(from, to)?, pool.period as u64)
+}
+
+/// Portion of `amount` vested at `now` under a linear schedule that starts at
+/// `start_time`, releases nothing before `cliff` seconds have passed and
+/// releases everything once `duration` seconds have passed.
+pub fn vested_amount(amount : u64, start_time : i64, cliff : i64, duration : i64, now : i64) -> Result<u64, ProgramError> {
+    let elapsed = now.saturating_sub(start_time);
+    if elapsed < cliff {
+        return Ok(0);
+    }
+    if elapsed >= duration {
+        return Ok(amount);
+    }
+    mul_div(amount, elapsed as u64, duration as u64)
+}
+
+/// Length of `[from, to]` in seconds; `to` must not precede `from`.
+fn seconds_between(from : i64, to : i64) -> Result<u64, ProgramError> {
+    to.checked_sub(from)
+        .and_then(|seconds| u64::try_from(seconds).ok())
+        .ok_or_else(|| PoolError::MathOverflow.into())
+}
+
+/// Value of `reward_per_weight` once emissions up to `now` are distributed.
+///
+/// `emission_rate` tokens are released per period and split across
+/// `total_weight`, between the pool's `start_time` and `end_time`. Time during
+/// which nothing is staked is not distributed, so those tokens simply stay in
+/// the vault.
+pub fn accumulate(emission : &EmissionSnapshot, now : i64) -> Result<u128, ProgramError> {
+    if emission.period <= 0 {
+        return Err(PoolError::InvalidPeriod.into());
+    }
+    let from = emission.last_update_time.max(emission.start_time);
+    let to = if emission.end_time == 0 { now } else { now.min(emission.end_time) };
+    if emission.total_weight == 0 || to <= from {
+        return Ok(emission.reward_per_weight);
+    }
+
+    let seconds = scheduled_seconds(emission.schedule, emission.start_time, emission.period, from, to)?;
+    let emitted = scaled_reward(emission.emission_rate, seconds, emission.period)?;
+    let increment = (emitted as u128)
+        .checked_mul(ACC_PRECISION)
+        .ok_or(PoolError::MathOverflow)?
+        / emission.total_weight as u128;
+
+    emission.reward_per_weight.checked_add(increment).ok_or_else(|| PoolError::MathOverflow.into())
+}
+
+/// Accumulator value already accounted for a stake of `weight`.
+pub fn reward_debt(weight : u64, reward_per_weight : u128) -> Result<u128, ProgramError> {
+    (weight as u128)
+        .checked_mul(reward_per_weight)
+        .map(|scaled| scaled / ACC_PRECISION)
+        .ok_or_else(|| PoolError::MathOverflow.into())
+}
+
+/// Rewards a shared-mode stake has earned since its debt was last set.
+pub fn pending_shared(weight : u64, reward_per_weight : u128, debt : u128) -> Result<u64, ProgramError> {
+    let accrued = reward_debt(weight, reward_per_weight)?.saturating_sub(debt);
+    u64::try_from(accrued).map_err(|_| PoolError::MathOverflow.into())
+}
+
+/// Share weight of a stake in a shared-mode pool, in basis points.
+///
+/// The stake's rarity multiplier, further scaled by its lock option's
+/// `multiplier_bps` while it is inside its lock. The weight is only refreshed
+/// when the stake is touched, so a lock bonus keeps applying after the lock
+/// expires until the stake claims or anyone calls `refresh_weight` on it.
+pub fn stake_weight(
+    stake : &StakeSnapshot,
+    collection : &CollectionSnapshot,
+    pool : &PoolSnapshot,
+    now : i64,
+) -> Result<u64, ProgramError> {
+    let mut lock_multiplier = BPS;
+    if let Some(end) = lock_end(stake, pool)? {
+        if now < end {
+            if let Some(option) = collection.lock_options.iter().find(|option| option.duration == stake.lock_period) {
+                lock_multiplier = option.multiplier_bps.map(|bps| bps as u64).unwrap_or(BPS);
+            }
+        }
+    }
+    mul_div(lock_multiplier, stake.multiplier_bps, BPS)
+}
+
+/// Seconds between `from` and `to`, each weighted by the schedule's rate in
+/// basis points at that moment. Time before `start_time` runs at the full
+/// rate.
+pub fn scheduled_seconds(
+    schedule : &EmissionSchedule,
+    start_time : i64,
+    period : i64,
+    from : i64,
+    to : i64,
+) -> Result<u128, ProgramError> {
+    if period <= 0 {
+        return Err(PoolError::InvalidPeriod.into());
+    }
+    if to <= from {
+        return Ok(0);
+    }
+
+    let before_start = seconds_between(from.min(start_time), to.min(start_time))? as u128 * BPS as u128;
+    let begin = seconds_between(start_time, from.max(start_time))?;
+    let end = seconds_between(start_time, to.max(start_time))?;
+
+    let scheduled = cumulative_rate(schedule, period as u64, end)?
+        .checked_sub(cumulative_rate(schedule, period as u64, begin)?)
+        .ok_or(PoolError::MathOverflow)?;
+    before_start.checked_add(scheduled).ok_or_else(|| PoolError::MathOverflow.into())
+}
+
+/// Integral of the schedule's rate (in basis points) over the first `elapsed`
+/// seconds after the pool starts.
+fn cumulative_rate(schedule : &EmissionSchedule, period : u64, elapsed : u64) -> Result<u128, ProgramError> {
+    let elapsed = elapsed as u128;
+    let period = period as u128;
+    let bps = BPS as u128;
+
+    let total = match schedule {
+        EmissionSchedule::Flat => elapsed.checked_mul(bps),
+        EmissionSchedule::StepHalving { interval } => {
+            let span = period.checked_mul(*interval as u128).ok_or(PoolError::MathOverflow)?;
+            let halvings = elapsed.checked_div(span).ok_or(PoolError::InvalidSchedule)?;
+            let mut total : u128 = 0;
+            let mut rate = bps;
+            let mut i = 0;
+            while i < halvings && rate > 0 {
+                total = span
+                    .checked_mul(rate)
+                    .and_then(|halving| total.checked_add(halving))
+                    .ok_or(PoolError::MathOverflow)?;
+                rate >>= 1;
+                i += 1;
+            }
+            if i == halvings {
+                halvings
+                    .checked_mul(span)
+                    .and_then(|halved| elapsed.checked_sub(halved))
+                    .and_then(|tail| tail.checked_mul(rate))
+                    .and_then(|tail| total.checked_add(tail))
+            } else {
+                Some(total)
+            }
+        }
+        EmissionSchedule::LinearDecay { decay_bps, floor_bps } => {
+            let decay = *decay_bps as u128;
+            let floor = (*floor_bps as u128).min(bps);
+            let periods = elapsed / period;
+            let remainder = elapsed % period;
+            // Number of periods before the rate reaches the floor.
+            let decaying = if decay == 0 { periods } else { ((bps - floor + decay - 1) / decay).min(periods) };
+            let decayed_sum = decaying
+                .checked_mul(bps)
+                .ok_or(PoolError::MathOverflow)?
+                - decay * (decaying * decaying.saturating_sub(1) / 2);
+            let rate_at = |index : u128| bps.saturating_sub(decay.saturating_mul(index)).max(floor);
+            period
+                .checked_mul(decayed_sum + (periods - decaying) * floor)
+                .and_then(|full| full.checked_add(remainder * rate_at(periods)))
+        }
+        EmissionSchedule::Piecewise { steps } => {
+            let mut total : u128 = 0;
+            let mut cursor : u128 = 0;
+            let mut rate = bps;
+            for step in steps {
+                let step_start = (step.start_period as u128).checked_mul(period).ok_or(PoolError::MathOverflow)?;
+                if step_start >= elapsed {
+                    break;
+                }
+                total = total.checked_add((step_start - cursor).checked_mul(rate).ok_or(PoolError::MathOverflow)?).ok_or(PoolError::MathOverflow)?;
+                cursor = step_start;
+                rate = step.rate_bps as u128;
+            }
+            (elapsed - cursor).checked_mul(rate).and_then(|tail| total.checked_add(tail))
+        }
+    };
+    total.ok_or_else(|| PoolError::MathOverflow.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ScheduleStep;
+
+    const PERIOD : i64 = 100;
+    // 10 tokens a second at the normal rate, 30 a second while locked.
+    const REWARD_NORMAL : u64 = 1000;
+    const LOCK_REWARD : u64 = 3000;
+
+    fn pool(schedule : &EmissionSchedule) -> PoolSnapshot {
+        PoolSnapshot {
+            start_time : 0,
+            end_time : 0,
+            period : PERIOD,
+            schedule,
+        }
+    }
+
+    fn collection<'a>(lock_options : &'a [LockOption], rate_history : &'a [RateCheckpoint]) -> CollectionSnapshot<'a> {
+        CollectionSnapshot {
+            start_time : 0,
+            end_time : 0,
+            reward_normal : REWARD_NORMAL,
+            lock_options,
+            rate_history,
+        }
+    }
+
+    fn lock_option(duration : u64, reward : u64) -> LockOption {
+        LockOption {
+            duration,
+            reward,
+            ..LockOption::default()
+        }
+    }
+
+    fn stake(lock_period : Option<u64>, lock_start : i64, last_claim_time : i64) -> StakeSnapshot {
+        StakeSnapshot {
+            mint : Pubkey::default(),
+            multiplier_bps : BPS,
+            locked : lock_period.is_some(),
+            lock_period : lock_period.unwrap_or(0),
+            lock_start,
+            last_claim_time,
+            unbond_time : 0,
+        }
+    }
+
+    fn breakdown(locked_reward : u64, normal_reward : u64) -> RewardBreakdown {
+        RewardBreakdown {
+            locked_reward,
+            normal_reward,
+            multiplier_bps : BPS,
+        }
+    }
+
+    #[test]
+    fn claim_straddling_lock_expiry_pays_both_rates() {
+        let options = [lock_option(5, LOCK_REWARD)];
+        // Lock runs until 500: 200 locked seconds, then 300 normal ones.
+        let reward = calculate_reward(&stake(Some(5), 0, 300), &collection(&options, &[]), &pool(&EmissionSchedule::Flat), 800).unwrap();
+        assert_eq!(reward, breakdown(6000, 3000));
+    }
+
+    #[test]
+    fn claim_split_anywhere_pays_the_same_as_one_claim() {
+        let options = [lock_option(5, LOCK_REWARD)];
+        let schedule = EmissionSchedule::Flat;
+        let whole = calculate_reward(&stake(Some(5), 0, 300), &collection(&options, &[]), &pool(&schedule), 800).unwrap();
+        for split in (300..=800).step_by(37) {
+            let first = calculate_reward(&stake(Some(5), 0, 300), &collection(&options, &[]), &pool(&schedule), split).unwrap();
+            let second = calculate_reward(&stake(Some(5), 0, split), &collection(&options, &[]), &pool(&schedule), 800).unwrap();
+            assert_eq!(first.locked_reward + second.locked_reward, whole.locked_reward, "split at {}", split);
+            assert_eq!(first.normal_reward + second.normal_reward, whole.normal_reward, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn first_claim_counts_from_stake_time() {
+        let options = [lock_option(5, LOCK_REWARD)];
+        let schedule = EmissionSchedule::Flat;
+        let unlocked = calculate_reward(&stake(None, 1000, 1000), &collection(&options, &[]), &pool(&schedule), 1250).unwrap();
+        assert_eq!(unlocked, breakdown(0, 2500));
+        let locked = calculate_reward(&stake(Some(5), 1000, 1000), &collection(&options, &[]), &pool(&schedule), 1250).unwrap();
+        assert_eq!(locked, breakdown(7500, 0));
+    }
+
+    #[test]
+    fn unbonding_cooldown_earns_nothing() {
+        let schedule = EmissionSchedule::Flat;
+        // Staked at 0, unstake requested (and settled) at 500, cooldown of
+        // 300 over at 800 when the NFT is unstaked.
+        let mut requested = stake(None, 0, 500);
+        requested.unbond_time = 500;
+        assert_eq!(calculate_reward(&requested, &collection(&[], &[]), &pool(&schedule), 800).unwrap(), breakdown(0, 0));
+
+        // Rewards up to the request still count if they were never settled.
+        let mut unsettled = stake(None, 0, 300);
+        unsettled.unbond_time = 500;
+        assert_eq!(calculate_reward(&unsettled, &collection(&[], &[]), &pool(&schedule), 800).unwrap(), breakdown(0, 2000));
+    }
+
+    #[test]
+    fn zero_length_windows_earn_nothing() {
+        let options = [lock_option(5, LOCK_REWARD)];
+        let schedule = EmissionSchedule::Flat;
+        let same_time = calculate_reward(&stake(Some(5), 0, 300), &collection(&options, &[]), &pool(&schedule), 300).unwrap();
+        assert_eq!(same_time, breakdown(0, 0));
+
+        let mut closed = collection(&options, &[]);
+        closed.start_time = 500;
+        closed.end_time = 500;
+        assert_eq!(calculate_reward(&stake(None, 0, 0), &closed, &pool(&schedule), 800).unwrap(), breakdown(0, 0));
+
+        let mut ended = pool(&schedule);
+        ended.end_time = 200;
+        assert_eq!(calculate_reward(&stake(None, 0, 300), &collection(&options, &[]), &ended, 800).unwrap(), breakdown(0, 0));
+    }
+
+    #[test]
+    fn non_positive_period_is_rejected() {
+        let schedule = EmissionSchedule::Flat;
+        for period in [0, -1, i64::MIN].iter().copied() {
+            let mut bad = pool(&schedule);
+            bad.period = period;
+            let err : ProgramError = PoolError::InvalidPeriod.into();
+            assert_eq!(calculate_reward(&stake(None, 0, 0), &collection(&[], &[]), &bad, 100).unwrap_err(), err);
+            assert_eq!(calculate_stream_reward(100, BPS, &bad, 0, 100).unwrap_err(), err);
+            assert_eq!(scaled_reward(100, 100, period).unwrap_err(), err);
+            assert_eq!(scheduled_seconds(&schedule, 0, period, 0, 100).unwrap_err(), err);
+
+            let emission = EmissionSnapshot {
+                start_time : 0,
+                end_time : 0,
+                period,
+                schedule : &schedule,
+                emission_rate : 100,
+                reward_per_weight : 0,
+                total_weight : 1,
+                last_update_time : 0,
+            };
+            assert_eq!(accumulate(&emission, 100).unwrap_err(), err);
+        }
+    }
+
+    #[test]
+    fn checkpoints_price_each_span_at_its_own_rates() {
+        let history = [RateCheckpoint {
+            until : 500,
+            reward_normal : REWARD_NORMAL / 2,
+            lock_options : vec![],
+        }];
+        let schedule = EmissionSchedule::Flat;
+        // 200 seconds at 5 a second, then 300 at 10.
+        let reward = calculate_reward(&stake(None, 0, 300), &collection(&[], &history), &pool(&schedule), 800).unwrap();
+        assert_eq!(reward, breakdown(0, 4000));
+        // A claim made after the checkpoint only sees the current rate.
+        let reward = calculate_reward(&stake(None, 0, 600), &collection(&[], &history), &pool(&schedule), 800).unwrap();
+        assert_eq!(reward, breakdown(0, 2000));
+    }
+
+    #[test]
+    fn checkpoints_split_lock_rates_too() {
+        let history = [RateCheckpoint {
+            until : 400,
+            reward_normal : REWARD_NORMAL,
+            lock_options : vec![lock_option(5, LOCK_REWARD)],
+        }];
+        let options = [lock_option(5, 5000)];
+        // [300, 400] at the old lock rate, [400, 500] at the new one, then normal.
+        let reward = calculate_reward(&stake(Some(5), 0, 300), &collection(&options, &history), &pool(&EmissionSchedule::Flat), 600).unwrap();
+        assert_eq!(reward, breakdown(8000, 1000));
+    }
+
+    #[test]
+    fn checkpoint_split_is_additive() {
+        let history = [
+            RateCheckpoint { until : 350, reward_normal : 700, lock_options : vec![] },
+            RateCheckpoint { until : 620, reward_normal : 1300, lock_options : vec![] },
+        ];
+        let schedule = EmissionSchedule::Flat;
+        let whole = calculate_reward(&stake(None, 0, 100), &collection(&[], &history), &pool(&schedule), 900).unwrap();
+        for split in (100..=900).step_by(23) {
+            let first = calculate_reward(&stake(None, 0, 100), &collection(&[], &history), &pool(&schedule), split).unwrap();
+            let second = calculate_reward(&stake(None, 0, split), &collection(&[], &history), &pool(&schedule), 900).unwrap();
+            assert_eq!(first.total().unwrap() + second.total().unwrap(), whole.total().unwrap(), "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn vesting_waits_for_the_cliff_then_runs_linearly() {
+        assert_eq!(vested_amount(1000, 100, 50, 200, 0).unwrap(), 0);
+        assert_eq!(vested_amount(1000, 100, 50, 200, 149).unwrap(), 0);
+        assert_eq!(vested_amount(1000, 100, 50, 200, 150).unwrap(), 250);
+        assert_eq!(vested_amount(1000, 100, 50, 200, 200).unwrap(), 500);
+        assert_eq!(vested_amount(1000, 100, 50, 200, 300).unwrap(), 1000);
+        assert_eq!(vested_amount(1000, 100, 50, 200, i64::MAX).unwrap(), 1000);
+    }
+
+    #[test]
+    fn shared_emissions_split_by_weight() {
+        let schedule = EmissionSchedule::Flat;
+        let emission = EmissionSnapshot {
+            start_time : 0,
+            end_time : 0,
+            period : PERIOD,
+            schedule : &schedule,
+            emission_rate : REWARD_NORMAL,
+            reward_per_weight : 0,
+            total_weight : 3 * BPS,
+            last_update_time : 0,
+        };
+        // 300 seconds at 10 tokens a second, split one to two.
+        let reward_per_weight = accumulate(&emission, 300).unwrap();
+        assert_eq!(pending_shared(BPS, reward_per_weight, 0).unwrap(), 1000);
+        assert_eq!(pending_shared(2 * BPS, reward_per_weight, 0).unwrap(), 2000);
+        let debt = reward_debt(BPS, reward_per_weight).unwrap();
+        assert_eq!(pending_shared(BPS, reward_per_weight, debt).unwrap(), 0);
+
+        let empty = EmissionSnapshot { total_weight : 0, ..emission };
+        assert_eq!(accumulate(&empty, 300).unwrap(), 0);
+    }
+
+    #[test]
+    fn lock_bonus_weight_ends_with_the_lock() {
+        let options = [LockOption { multiplier_bps : Some(20000), ..lock_option(5, LOCK_REWARD) }];
+        let schedule = EmissionSchedule::Flat;
+        let locked = stake(Some(5), 0, 0);
+        assert_eq!(stake_weight(&locked, &collection(&options, &[]), &pool(&schedule), 499).unwrap(), 20000);
+        assert_eq!(stake_weight(&locked, &collection(&options, &[]), &pool(&schedule), 500).unwrap(), BPS);
+        let rare = StakeSnapshot { multiplier_bps : 15000, ..stake(None, 0, 0) };
+        assert_eq!(stake_weight(&rare, &collection(&options, &[]), &pool(&schedule), 0).unwrap(), 15000);
+    }
+
+    #[test]
+    fn mul_div_uses_a_wide_intermediate() {
+        assert_eq!(mul_div(7, 3, 2).unwrap(), 10);
+        assert_eq!(mul_div(u64::MAX, 3, 3).unwrap(), u64::MAX);
+        assert!(mul_div(u64::MAX, 2, 1).is_err());
+        assert!(mul_div(1, 1, 0).is_err());
+    }
+
+    #[test]
+    fn payouts_leave_other_stakes_debt_in_the_vault() {
+        // Two stakes are owed 300 and 200 by an empty vault.
+        let mut total_owed = 500;
+        let (mut first, mut second) = (300, 200);
+
+        // A refund of 600 covers both debts with 100 to spare, so a third
+        // stake claiming 250 new rewards gets 100 and is owed the rest.
+        let available = 600;
+        let paid = payout(0, 250, total_owed, available);
+        assert_eq!(paid, 100);
+        total_owed += 250 - paid;
+        let available = available - paid;
+
+        // The first two can still be paid in full.
+        let paid = payout(first, 0, total_owed - first, available);
+        assert_eq!(paid, 300);
+        total_owed -= first;
+        first = 0;
+        let available = available - paid;
+        let paid = payout(second, 0, total_owed - second, available);
+        assert_eq!(paid, 200);
+        total_owed -= second;
+        second = 0;
+        assert_eq!((first, second, total_owed, available - paid), (0, 0, 150, 0));
+
+        // A stake's own debt is paid before its new reward, and neither
+        // dips into what is reserved for others.
+        assert_eq!(payout(100, 100, 50, 120), 100);
+        assert_eq!(payout(100, 100, 50, 180), 130);
+        assert_eq!(payout(100, 100, 500, 50), 50);
+    }
+
+    #[test]
+    fn group_shares_never_exceed_the_royalty_share() {
+        assert_eq!(group_share(1000, 5000, 4).unwrap(), 125);
+        assert!(group_share(1000, 5000, 0).is_err());
+        assert!(group_share(1000, 5000, u64::MAX).is_err());
+        for royalty in [0, 1, 999, 123_456_789, u64::MAX].iter().copied() {
+            for count in 1..=7 {
+                let share = group_share(royalty, 3333, count).unwrap() as u128;
+                assert!(share * count as u128 <= royalty as u128 * 3333 / BPS as u128);
+            }
+        }
+    }
+
+    fn rate_at(schedule : &EmissionSchedule, period : u64, second : u64) -> u128 {
+        let bps = BPS as u128;
+        let index = second / period;
+        match schedule {
+            EmissionSchedule::Flat => bps,
+            EmissionSchedule::StepHalving { interval } => bps.checked_shr((index / interval) as u32).unwrap_or(0),
+            EmissionSchedule::LinearDecay { decay_bps, floor_bps } => {
+                bps.saturating_sub(*decay_bps as u128 * index as u128).max((*floor_bps as u128).min(bps))
+            }
+            EmissionSchedule::Piecewise { steps } => steps
+                .iter()
+                .filter(|step| step.start_period <= index)
+                .last()
+                .map_or(bps, |step| step.rate_bps as u128),
+        }
+    }
+
+    fn brute_cumulative_rate(schedule : &EmissionSchedule, period : u64, elapsed : u64) -> u128 {
+        (0..elapsed).map(|second| rate_at(schedule, period, second)).sum()
+    }
+
+    fn schedules() -> Vec<EmissionSchedule> {
+        vec![
+            EmissionSchedule::Flat,
+            EmissionSchedule::StepHalving { interval : 1 },
+            EmissionSchedule::StepHalving { interval : 3 },
+            EmissionSchedule::LinearDecay { decay_bps : 0, floor_bps : 0 },
+            EmissionSchedule::LinearDecay { decay_bps : 1500, floor_bps : 2000 },
+            EmissionSchedule::LinearDecay { decay_bps : 3000, floor_bps : 0 },
+            EmissionSchedule::LinearDecay { decay_bps : BPS, floor_bps : BPS },
+            EmissionSchedule::Piecewise { steps : vec![] },
+            EmissionSchedule::Piecewise {
+                steps : vec![
+                    ScheduleStep { start_period : 2, rate_bps : 5000 },
+                    ScheduleStep { start_period : 5, rate_bps : 15000 },
+                    ScheduleStep { start_period : 9, rate_bps : 0 },
+                ],
+            },
+        ]
+    }
+
+    #[test]
+    fn cumulative_rate_matches_per_second_sum() {
+        for schedule in schedules() {
+            for period in [1, 7, 10].iter().copied() {
+                for elapsed in 0..=150 {
+                    assert_eq!(
+                        cumulative_rate(&schedule, period, elapsed).unwrap(),
+                        brute_cumulative_rate(&schedule, period, elapsed),
+                        "period {} elapsed {}", period, elapsed,
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn halving_stops_once_the_rate_reaches_zero() {
+        let schedule = EmissionSchedule::StepHalving { interval : 1 };
+        let total : u128 = (0..14).map(|halvings| (BPS as u128) >> halvings).sum();
+        assert_eq!(cumulative_rate(&schedule, 1, 1_000_000).unwrap(), total);
+    }
+
+    #[test]
+    fn extreme_schedules_stay_in_range() {
+        let halving = EmissionSchedule::StepHalving { interval : 1 };
+        assert_eq!(cumulative_rate(&halving, 1, u64::MAX).unwrap(), 19_995);
+        let halving = EmissionSchedule::StepHalving { interval : u64::MAX };
+        assert_eq!(cumulative_rate(&halving, u64::MAX, u64::MAX).unwrap(), u64::MAX as u128 * BPS as u128);
+        let steps = vec![ScheduleStep { start_period : 0, rate_bps : u64::MAX }];
+        let piecewise = EmissionSchedule::Piecewise { steps };
+        assert_eq!(cumulative_rate(&piecewise, 1, u64::MAX).unwrap(), u64::MAX as u128 * u64::MAX as u128);
+    }
+
+    #[test]
+    fn scheduled_seconds_are_additive() {
+        for schedule in schedules() {
+            let whole = scheduled_seconds(&schedule, 50, 10, 0, 300).unwrap();
+            for split in (0..=300).step_by(13) {
+                let first = scheduled_seconds(&schedule, 50, 10, 0, split).unwrap();
+                let second = scheduled_seconds(&schedule, 50, 10, split, 300).unwrap();
+                assert_eq!(first + second, whole, "split at {}", split);
+            }
+        }
+    }
+}